@@ -0,0 +1,510 @@
+//! Axpert / MasterPower inverter simulator for development and testing.
+//!
+//! Answers the inquiry and setter commands used by MPQTT with CRC framed responses over TCP, so the
+//! whole init / update loop can run without an inverter. Point MPQTT at it with
+//! `inverter.path: tcp://127.0.0.1:5000`.
+//!
+//! Usage: `mpqtt-simulator [address] [scenario]`
+//!
+//! The scenario is either a built-in one (`normal`, `grid-loss`, `battery-draining`, `fault`) or a
+//! file where every line is `<seconds> <field> <value>`, applied once that many seconds have passed
+//! since the simulator started. Fields are the names used in the MPQTT json payloads, QPIWS flags
+//! take `0` or `1`. The special `nak` field makes the simulator answer `NAK` to a command, or to
+//! none of them with `nak none`. `parallel_units` sets how many units answer QPGSn, 2 by default.
+#![warn(clippy::all)]
+
+use crc_any::CRCu16;
+use log::{debug, error, info, warn};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:5000";
+
+const GRID_LOSS: &str = "
+10 grid_voltage 0
+10 grid_frequency 0
+10 mode B
+10 line_fail 1
+60 grid_voltage 230.0
+60 grid_frequency 50.0
+60 mode L
+60 line_fail 0
+";
+
+const BATTERY_DRAINING: &str = "
+0 grid_voltage 0
+0 grid_frequency 0
+0 mode B
+0 battery_charge_current 0
+0 battery_discharge_current 40
+0 line_fail 1
+20 battery_capacity 75
+20 battery_voltage 50.10
+40 battery_capacity 50
+40 battery_voltage 48.60
+60 battery_capacity 25
+60 battery_voltage 46.80
+60 battery_low_alarm 1
+80 battery_capacity 0
+80 battery_voltage 42.00
+80 battery_discharge_current 0
+80 ac_out_voltage 0
+80 ac_out_active_power 0
+80 ac_out_apparent_power 0
+80 battery_under_shutdown 1
+80 mode F
+";
+
+const FAULT: &str = "
+10 inverter_heat_sink_temp 95
+10 over_temperature 1
+20 fan_locked 1
+20 inverter_fault 1
+20 mode F
+";
+
+/// QPIWS flags in bit order, reserved bits are empty
+const WARNINGS: [&str; 32] = [
+    "",
+    "inverter_fault",
+    "bus_over",
+    "bus_under",
+    "bus_soft_fail",
+    "line_fail",
+    "opv_short",
+    "inverter_voltage_too_low",
+    "inverter_voltage_too_high",
+    "over_temperature",
+    "fan_locked",
+    "battery_voltage_high",
+    "battery_low_alarm",
+    "",
+    "battery_under_shutdown",
+    "",
+    "over_load",
+    "eeprom_fault",
+    "inverter_over_current",
+    "inverter_soft_fail",
+    "self_test_fail",
+    "op_dc_voltage_over",
+    "bat_open",
+    "current_sensor_fail",
+    "battery_short",
+    "power_limit",
+    "pv_voltage_high",
+    "mppt_overload_fault",
+    "mppt_overload_warning",
+    "battery_too_low_to_charge",
+    "",
+    "",
+];
+
+/// QFLAG letters, enabled ones are kept in `State::flags`
+const FLAGS: &str = "abjkuvxyz";
+
+struct Step {
+    seconds: u64,
+    field: String,
+    value: String,
+}
+
+struct State {
+    // QMOD
+    mode: char,
+
+    // QPIGS
+    grid_voltage: f32,
+    grid_frequency: f32,
+    ac_out_voltage: f32,
+    ac_out_frequency: f32,
+    ac_out_apparent_power: u32,
+    ac_out_active_power: u32,
+    battery_voltage: f32,
+    battery_charge_current: u32,
+    battery_capacity: u32,
+    inverter_heat_sink_temp: u32,
+    pv_input_current: u32,
+    pv_input_voltage: f32,
+    battery_discharge_current: u32,
+
+    // QPIRI
+    battery_recharge_voltage: f32,
+    battery_under_voltage: f32,
+    battery_bulk_voltage: f32,
+    battery_float_voltage: f32,
+    battery_redischarge_voltage: f32,
+    battery_type: u8,
+    max_ac_charging_current: u32,
+    max_charging_current: u32,
+    input_voltage_range: u8,
+    output_source_priority: u8,
+    charge_source_priority: u8,
+
+    // QPIWS and QFLAG
+    warnings: [bool; 32],
+    flags: String,
+
+    // QPGSn, every unit reports the same values
+    parallel_units: u8,
+
+    // Command answered with NAK
+    nak: Option<String>,
+
+    // Scenario
+    start: Instant,
+    steps: Vec<Step>,
+    next_step: usize,
+}
+
+impl State {
+    fn new(steps: Vec<Step>) -> Self {
+        State {
+            mode: 'L',
+            grid_voltage: 230.0,
+            grid_frequency: 50.0,
+            ac_out_voltage: 230.0,
+            ac_out_frequency: 50.0,
+            ac_out_apparent_power: 460,
+            ac_out_active_power: 420,
+            battery_voltage: 52.8,
+            battery_charge_current: 12,
+            battery_capacity: 100,
+            inverter_heat_sink_temp: 38,
+            pv_input_current: 8,
+            pv_input_voltage: 180.5,
+            battery_discharge_current: 0,
+            battery_recharge_voltage: 46.0,
+            battery_under_voltage: 42.0,
+            battery_bulk_voltage: 56.4,
+            battery_float_voltage: 54.0,
+            battery_redischarge_voltage: 54.0,
+            battery_type: 2,
+            max_ac_charging_current: 30,
+            max_charging_current: 60,
+            input_voltage_range: 0,
+            output_source_priority: 2,
+            charge_source_priority: 1,
+            warnings: [false; 32],
+            flags: "axyz".to_string(),
+            parallel_units: 2,
+            nak: None,
+            start: Instant::now(),
+            steps,
+            next_step: 0,
+        }
+    }
+
+    /// Applies every scenario step that is due
+    fn advance(&mut self) {
+        let elapsed = self.start.elapsed().as_secs();
+        while let Some(step) = self.steps.get(self.next_step).filter(|step| step.seconds <= elapsed) {
+            let (field, value) = (step.field.clone(), step.value.clone());
+            info!("Scenario at {}s: {} = {}", step.seconds, field, value);
+            if let Err(error) = self.set(&field, &value) {
+                warn!("Skipping scenario step: {}", error);
+            }
+            self.next_step += 1;
+        }
+    }
+
+    fn set(&mut self, field: &str, value: &str) -> Result<(), String> {
+        match field {
+            "mode" => self.mode = parse(field, value)?,
+            "grid_voltage" => self.grid_voltage = parse(field, value)?,
+            "grid_frequency" => self.grid_frequency = parse(field, value)?,
+            "ac_out_voltage" => self.ac_out_voltage = parse(field, value)?,
+            "ac_out_frequency" => self.ac_out_frequency = parse(field, value)?,
+            "ac_out_apparent_power" => self.ac_out_apparent_power = parse(field, value)?,
+            "ac_out_active_power" => self.ac_out_active_power = parse(field, value)?,
+            "battery_voltage" => self.battery_voltage = parse(field, value)?,
+            "battery_charge_current" => self.battery_charge_current = parse(field, value)?,
+            "battery_capacity" => self.battery_capacity = parse(field, value)?,
+            "inverter_heat_sink_temp" => self.inverter_heat_sink_temp = parse(field, value)?,
+            "pv_input_current" => self.pv_input_current = parse(field, value)?,
+            "pv_input_voltage" => self.pv_input_voltage = parse(field, value)?,
+            "battery_discharge_current" => self.battery_discharge_current = parse(field, value)?,
+            "parallel_units" => self.parallel_units = parse(field, value)?,
+            "nak" => self.nak = Some(value.to_string()).filter(|command| command != "none"),
+            _ => {
+                let bit = WARNINGS.iter().position(|warning| !warning.is_empty() && *warning == field).ok_or_else(|| format!("Unknown field {}", field))?;
+                self.warnings[bit] = parse::<u8>(field, value)? == 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn qpiri(&self) -> String {
+        format!(
+            "230.0 21.7 230.0 50.0 21.7 5000 4000 48.0 {:04.1} {:04.1} {:04.1} {:04.1} {} {:02} {:03} {} {} {} 1 00 0 0 {:04.1} 0 1",
+            self.battery_recharge_voltage,
+            self.battery_under_voltage,
+            self.battery_bulk_voltage,
+            self.battery_float_voltage,
+            self.battery_type,
+            self.max_ac_charging_current,
+            self.max_charging_current,
+            self.input_voltage_range,
+            self.output_source_priority,
+            self.charge_source_priority,
+            self.battery_redischarge_voltage,
+        )
+    }
+
+    fn qpigs(&self) -> String {
+        let load_percent = self.ac_out_apparent_power * 100 / 5000;
+        let charging = self.battery_charge_current > 0;
+        let scc_charging = charging && self.pv_input_current > 0;
+        let ac_charging = charging && self.mode == 'L';
+        let device_status = format!("0001{}{}{}{}", charging as u8, charging as u8, scc_charging as u8, ac_charging as u8);
+        let pv_charging_power = (self.pv_input_current as f32 * self.pv_input_voltage) as u32;
+
+        format!(
+            "{:05.1} {:04.1} {:05.1} {:04.1} {:04} {:04} {:03} 460 {:05.2} {:03} {:03} {:04} {:04} {:05.1} {:05.2} {:05} {} 00 00 {:05} 010",
+            self.grid_voltage,
+            self.grid_frequency,
+            self.ac_out_voltage,
+            self.ac_out_frequency,
+            self.ac_out_apparent_power,
+            self.ac_out_active_power,
+            load_percent,
+            self.battery_voltage,
+            self.battery_charge_current,
+            self.battery_capacity,
+            self.inverter_heat_sink_temp,
+            self.pv_input_current,
+            self.pv_input_voltage,
+            self.battery_voltage,
+            self.battery_discharge_current,
+            device_status,
+            pv_charging_power,
+        )
+    }
+
+    /// Units outside of the parallel group are answered with NAK
+    fn qpgs(&self, unit: u8) -> Option<String> {
+        if unit >= self.parallel_units {
+            return None;
+        }
+
+        let load_percent = self.ac_out_apparent_power * 100 / 5000;
+        let charging = self.battery_charge_current > 0;
+        let scc_charging = charging && self.pv_input_current > 0;
+        let ac_charging = charging && self.mode == 'L';
+        let line_loss = self.grid_voltage == 0.0;
+        let inverter_status = format!("1{}{}00{}10", ac_charging as u8, scc_charging as u8, line_loss as u8);
+        let units = u32::from(self.parallel_units);
+
+        Some(format!(
+            "1 9293200410244{} {} 00 {:05.1} {:05.2} {:05.1} {:05.2} {:04} {:04} {:03} {:04.1} {:03} {:03} {:05.1} {:03} {:05} {:05} {:03} {} {} {} {:03} 080 {:02} {:02} {:03}",
+            unit,
+            self.mode,
+            self.grid_voltage,
+            self.grid_frequency,
+            self.ac_out_voltage,
+            self.ac_out_frequency,
+            self.ac_out_apparent_power,
+            self.ac_out_active_power,
+            load_percent,
+            self.battery_voltage,
+            self.battery_charge_current,
+            self.battery_capacity,
+            self.pv_input_voltage,
+            self.battery_charge_current * units,
+            self.ac_out_apparent_power * units,
+            self.ac_out_active_power * units,
+            load_percent,
+            inverter_status,
+            if self.parallel_units > 1 { 1 } else { 0 },
+            self.charge_source_priority,
+            self.max_charging_current,
+            self.max_ac_charging_current,
+            self.pv_input_current,
+            self.battery_discharge_current,
+        ))
+    }
+
+    fn qpiws(&self) -> String {
+        self.warnings.iter().map(|warning| if *warning { '1' } else { '0' }).collect()
+    }
+
+    fn qflag(&self) -> String {
+        let disabled: String = FLAGS.chars().filter(|flag| !self.flags.contains(*flag)).collect();
+        format!("E{}D{}", self.flags, disabled)
+    }
+
+    /// Answers a command without framing, `None` means the command is not supported or set to NAK
+    fn answer(&mut self, command: &str) -> Option<String> {
+        self.advance();
+        if self.nak.as_deref() == Some(command) {
+            return None;
+        }
+
+        let response = match command {
+            "QID" => "92932004102443".to_string(),
+            "QPI" => "PI30".to_string(),
+            "QVFW" => "VERFW:00072.70".to_string(),
+            "QVFW2" => "VERFW2:00000.00".to_string(),
+            "QMOD" => self.mode.to_string(),
+            "QPIRI" => self.qpiri(),
+            "QPIGS" => self.qpigs(),
+            "QPIWS" => self.qpiws(),
+            "QFLAG" => self.qflag(),
+            "QMCHGCR" => "010 020 030 040 050 060 070 080".to_string(),
+            "QMUCHGCR" => "002 010 020 030".to_string(),
+            _ if command.starts_with("QPGS") => return command[4..].parse().ok().and_then(|unit| self.qpgs(unit)),
+            _ => return self.apply(command).map(|ack| if ack { "ACK" } else { "NAK" }.to_string()),
+        };
+        Some(response)
+    }
+
+    /// Applies a setter command, returns whether it was accepted
+    fn apply(&mut self, command: &str) -> Option<bool> {
+        let setters: [&str; 13] = ["MUCHGC", "MCHGC", "PBCV", "PBDV", "PSDV", "PCVV", "PBFT", "PBT", "PGR", "POP", "PCP", "PE", "PD"];
+        let setter = setters.iter().find(|setter| command.starts_with(**setter))?;
+        let value = &command[setter.len()..];
+
+        let accepted = match *setter {
+            "MUCHGC" => value.parse::<u32>().map(|current| self.max_ac_charging_current = current).is_ok(),
+            "MCHGC" => value.parse::<u32>().map(|current| self.max_charging_current = current).is_ok(),
+            "PBCV" => value.parse::<f32>().map(|voltage| self.battery_recharge_voltage = voltage).is_ok(),
+            "PBDV" => value.parse::<f32>().map(|voltage| self.battery_redischarge_voltage = voltage).is_ok(),
+            "PSDV" => value.parse::<f32>().map(|voltage| self.battery_under_voltage = voltage).is_ok(),
+            "PCVV" => value.parse::<f32>().map(|voltage| self.battery_bulk_voltage = voltage).is_ok(),
+            "PBFT" => value.parse::<f32>().map(|voltage| self.battery_float_voltage = voltage).is_ok(),
+            "PBT" => value.parse::<u8>().ok().filter(|battery_type| *battery_type <= 2).map(|battery_type| self.battery_type = battery_type).is_some(),
+            "PGR" => value.parse::<u8>().ok().filter(|range| *range <= 1).map(|range| self.input_voltage_range = range).is_some(),
+            "POP" => value.parse::<u8>().ok().filter(|priority| *priority <= 2).map(|priority| self.output_source_priority = priority).is_some(),
+            "PCP" => value.parse::<u8>().ok().filter(|priority| *priority <= 3).map(|priority| self.charge_source_priority = priority).is_some(),
+            _ => {
+                let flag = value.chars().next().filter(|flag| value.len() == 1 && FLAGS.contains(*flag));
+                match (flag, *setter) {
+                    (Some(flag), "PE") if !self.flags.contains(flag) => self.flags.push(flag),
+                    (Some(flag), "PD") => self.flags.retain(|enabled| enabled != flag),
+                    (Some(_), _) => {}
+                    (None, _) => return Some(false),
+                }
+                true
+            }
+        };
+        Some(accepted)
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    if std::env::var("RUST_LOG").is_err() {
+        std::env::set_var("RUST_LOG", "info");
+    }
+    pretty_env_logger::init();
+
+    let mut args = std::env::args().skip(1);
+    let address = args.next().unwrap_or_else(|| DEFAULT_ADDRESS.to_string());
+    let scenario = args.next().unwrap_or_else(|| "normal".to_string());
+
+    let steps = load_scenario(&scenario)?;
+    info!("Loaded scenario {} with {} steps", scenario, steps.len());
+    let state = Arc::new(Mutex::new(State::new(steps)));
+
+    let mut listener = TcpListener::bind(&address).await?;
+    info!("Simulator listening on {}", address);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        info!("Accepted connection from {}", peer);
+
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(error) = serve(stream, state).await {
+                error!("Connection from {} failed: {}", peer, error);
+            }
+            info!("Connection from {} closed", peer);
+        });
+    }
+}
+
+fn parse<T: FromStr>(field: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid value '{}' for {}", value, field))
+}
+
+fn load_scenario(scenario: &str) -> Result<Vec<Step>, Box<dyn std::error::Error>> {
+    let script = match scenario {
+        "normal" => String::new(),
+        "grid-loss" => GRID_LOSS.to_string(),
+        "battery-draining" => BATTERY_DRAINING.to_string(),
+        "fault" => FAULT.to_string(),
+        path => std::fs::read_to_string(path)?,
+    };
+
+    let mut steps = Vec::new();
+    for (number, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        match parts.as_slice() {
+            [seconds, field, value] => steps.push(Step {
+                seconds: seconds.parse().map_err(|_| format!("Invalid time on line {}: {}", number + 1, line))?,
+                field: field.to_string(),
+                value: value.to_string(),
+            }),
+            _ => return Err(format!("Expected '<seconds> <field> <value>' on line {}: {}", number + 1, line).into()),
+        }
+    }
+
+    // Steps are applied in time order
+    steps.sort_by_key(|step| step.seconds);
+    Ok(steps)
+}
+
+async fn serve(stream: TcpStream, state: Arc<Mutex<State>>) -> Result<(), Box<dyn std::error::Error>> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut request = Vec::new();
+
+    loop {
+        request.clear();
+        if reader.read_until(b'\r', &mut request).await? == 0 {
+            return Ok(());
+        }
+
+        // Request is <command><crc><cr>
+        if request.len() < 4 {
+            warn!("Ignoring short request {:?}", request);
+            continue;
+        }
+        let (command, crc) = request[..request.len() - 1].split_at(request.len() - 3);
+        if crc != checksum(command) {
+            warn!("Ignoring request with invalid CRC {:?}", String::from_utf8_lossy(command));
+            continue;
+        }
+
+        let command = String::from_utf8_lossy(command).to_string();
+        let response = state.lock().unwrap().answer(&command).unwrap_or_else(|| "NAK".to_string());
+        debug!("{} -> {}", command, response);
+
+        // Response is (<data><crc><cr>
+        let mut frame = format!("({}", response).into_bytes();
+        let crc = checksum(&frame);
+        frame.extend_from_slice(&crc);
+        frame.push(b'\r');
+        writer.write_all(&frame).await?;
+    }
+}
+
+/// CRC-16/XMODEM, bytes that would clash with the framing characters are incremented like the inverter does
+fn checksum(data: &[u8]) -> [u8; 2] {
+    let mut crc = CRCu16::crc16xmodem();
+    crc.digest(data);
+    let mut bytes = crc.get_crc().to_be_bytes();
+    for byte in bytes.iter_mut() {
+        if matches!(*byte, b'(' | b'\r' | b'\n') {
+            *byte += 1;
+        }
+    }
+    bytes
+}
@@ -0,0 +1,123 @@
+use crate::settings::PublishSettings;
+
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+struct Published {
+    value: Value,
+    at: Instant,
+}
+
+/// Keeps the last published payload of each command to skip publishing values that did not change
+pub struct ChangeFilter {
+    on_change: bool,
+    refresh_interval: Duration,
+    deadband: HashMap<String, f64>,
+    last: HashMap<String, Published>,
+}
+
+impl ChangeFilter {
+    pub fn new(publish: &PublishSettings) -> Self {
+        ChangeFilter {
+            on_change: publish.on_change,
+            refresh_interval: Duration::from_secs(publish.refresh_interval * 60),
+            deadband: publish.deadband.clone(),
+            last: HashMap::new(),
+        }
+    }
+
+    /// Whether a payload has to be published, it is remembered as the last published one if so
+    pub fn should_publish(&mut self, command: &str, value: &Value) -> bool {
+        if !self.on_change {
+            return true;
+        }
+
+        let now = Instant::now();
+        if let Some(last) = self.last.get(command) {
+            if now.duration_since(last.at) < self.refresh_interval && !self.changed(None, &last.value, value) {
+                return false;
+            }
+        }
+
+        self.last.insert(command.to_string(), Published { value: value.clone(), at: now });
+        true
+    }
+
+    /// Numbers only count as changed when they moved more than the deadband of their field, anything else on any difference
+    fn changed(&self, field: Option<&str>, old: &Value, new: &Value) -> bool {
+        match (old, new) {
+            (Value::Object(old), Value::Object(new)) => old.len() != new.len() || new.iter().any(|(key, new)| old.get(key).map_or(true, |old| self.changed(Some(key), old, new))),
+            (Value::Number(old_number), Value::Number(new_number)) => match (old_number.as_f64(), new_number.as_f64()) {
+                (Some(old), Some(new)) => (new - old).abs() > field.and_then(|field| self.deadband.get(field)).copied().unwrap_or(0.0),
+                _ => old != new,
+            },
+            (old, new) => old != new,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn filter(on_change: bool, refresh_interval: u64) -> ChangeFilter {
+        let mut deadband = HashMap::new();
+        deadband.insert("battery_voltage".to_string(), 0.5);
+        ChangeFilter::new(&PublishSettings { on_change, refresh_interval, deadband })
+    }
+
+    #[test]
+    fn publishes_everything_when_disabled() {
+        let mut filter = filter(false, 5);
+        assert!(filter.should_publish("qpigs", &json!({ "battery_voltage": 52.0 })));
+        assert!(filter.should_publish("qpigs", &json!({ "battery_voltage": 52.0 })));
+    }
+
+    #[test]
+    fn skips_unchanged_payloads() {
+        let mut filter = filter(true, 5);
+        assert!(filter.should_publish("qpigs", &json!({ "battery_voltage": 52.0, "mode": "line" })));
+        assert!(!filter.should_publish("qpigs", &json!({ "battery_voltage": 52.0, "mode": "line" })));
+
+        // Each command keeps its own last payload
+        assert!(filter.should_publish("qmod", &json!({ "mode": "line" })));
+    }
+
+    #[test]
+    fn applies_the_deadband_of_each_field() {
+        let mut filter = filter(true, 5);
+        assert!(filter.should_publish("qpigs", &json!({ "battery_voltage": 52.0, "ac_out_active_power": 400 })));
+        assert!(!filter.should_publish("qpigs", &json!({ "battery_voltage": 52.4, "ac_out_active_power": 400 })));
+        assert!(filter.should_publish("qpigs", &json!({ "battery_voltage": 52.6, "ac_out_active_power": 400 })));
+
+        // Fields without a deadband change on any difference
+        assert!(filter.should_publish("qpigs", &json!({ "battery_voltage": 52.6, "ac_out_active_power": 401 })));
+    }
+
+    #[test]
+    fn compares_against_the_last_published_payload() {
+        let mut filter = filter(true, 5);
+        assert!(filter.should_publish("qpigs", &json!({ "battery_voltage": 52.0 })));
+        assert!(!filter.should_publish("qpigs", &json!({ "battery_voltage": 52.3 })));
+
+        // Small steps add up since the skipped payloads are not remembered
+        assert!(filter.should_publish("qpigs", &json!({ "battery_voltage": 52.6 })));
+    }
+
+    #[test]
+    fn detects_nested_and_added_fields() {
+        let mut filter = filter(true, 5);
+        assert!(filter.should_publish("qpigs", &json!({ "device_status": { "charge_status": "idle" } })));
+        assert!(filter.should_publish("qpigs", &json!({ "device_status": { "charge_status": "charging_floating" } })));
+        assert!(filter.should_publish("qpigs", &json!({ "device_status": { "charge_status": "charging_floating" }, "battery_voltage": 52.0 })));
+    }
+
+    #[test]
+    fn republishes_after_the_refresh_interval() {
+        let mut filter = filter(true, 0);
+        assert!(filter.should_publish("qpigs", &json!({ "battery_voltage": 52.0 })));
+        assert!(filter.should_publish("qpigs", &json!({ "battery_voltage": 52.0 })));
+    }
+}
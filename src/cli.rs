@@ -0,0 +1,118 @@
+use crate::settings::CONFIG_PATH;
+
+const LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+const USAGE: &str = "Usage: mpqtt [options]
+
+Options:
+    --config <path>       Configuration file to load
+    --check-config        Validate the configuration and exit
+    --once                Poll the inverter once, print the result as json and exit
+    --no-discovery        Don't publish Home Assistant discovery configs
+    --log-level <level>   Log level: off, error, warn, info, debug or trace
+    -h, --help            Print this help
+    -V, --version         Print the version";
+
+/// Command line arguments, they take precedence over the configuration file
+#[derive(Debug)]
+pub struct Args {
+    pub config: String,
+    pub check_config: bool,
+    pub once: bool,
+    pub no_discovery: bool,
+    pub log_level: Option<String>,
+}
+
+impl Args {
+    /// Parses the process arguments, printing the usage and exiting on `--help` or invalid arguments
+    pub fn parse() -> Self {
+        match Args::parse_from(std::env::args().skip(1)) {
+            Ok(args) => args,
+            Err(error) => {
+                eprintln!("{}\n\n{}", error, USAGE);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    fn parse_from<I: Iterator<Item = String>>(mut arguments: I) -> Result<Self, String> {
+        let mut args = Args {
+            config: CONFIG_PATH.to_string(),
+            check_config: false,
+            once: false,
+            no_discovery: false,
+            log_level: None,
+        };
+
+        while let Some(argument) = arguments.next() {
+            // Options with a value also accept `--option=value`
+            let (name, inline_value) = match argument.find('=') {
+                Some(index) if argument.starts_with("--") => (argument[..index].to_string(), Some(argument[index + 1..].to_string())),
+                _ => (argument.clone(), None),
+            };
+            let mut value = || inline_value.clone().or_else(|| arguments.next()).ok_or_else(|| format!("Missing value for {}", name));
+
+            match name.as_str() {
+                "--config" => args.config = value()?,
+                "--check-config" => args.check_config = true,
+                "--once" => args.once = true,
+                "--no-discovery" => args.no_discovery = true,
+                "--log-level" => {
+                    let level = value()?.to_ascii_lowercase();
+                    if !LOG_LEVELS.contains(&level.as_str()) {
+                        return Err(format!("Invalid log level {}", level));
+                    }
+                    args.log_level = Some(level);
+                }
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                "-V" | "--version" => {
+                    println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+                    std::process::exit(0);
+                }
+                _ => return Err(format!("Unknown argument {}", argument)),
+            }
+        }
+
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(arguments: &[&str]) -> Result<Args, String> {
+        Args::parse_from(arguments.iter().map(|argument| argument.to_string()))
+    }
+
+    #[test]
+    fn defaults_without_arguments() {
+        let args = parse(&[]).unwrap();
+        assert_eq!(args.config, CONFIG_PATH);
+        assert!(!args.check_config && !args.once && !args.no_discovery);
+        assert_eq!(args.log_level, None);
+    }
+
+    #[test]
+    fn parses_flags() {
+        let args = parse(&["--check-config", "--once", "--no-discovery"]).unwrap();
+        assert!(args.check_config && args.once && args.no_discovery);
+    }
+
+    #[test]
+    fn accepts_separate_and_inline_values() {
+        assert_eq!(parse(&["--config", "/tmp/mpqtt.yaml"]).unwrap().config, "/tmp/mpqtt.yaml");
+        assert_eq!(parse(&["--config=/tmp/mpqtt.yaml"]).unwrap().config, "/tmp/mpqtt.yaml");
+        assert_eq!(parse(&["--log-level=DEBUG"]).unwrap().log_level, Some("debug".to_string()));
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert_eq!(parse(&["--config"]).unwrap_err(), "Missing value for --config");
+        assert_eq!(parse(&["--log-level", "loud"]).unwrap_err(), "Invalid log level loud");
+        assert_eq!(parse(&["--verbose"]).unwrap_err(), "Unknown argument --verbose");
+    }
+}
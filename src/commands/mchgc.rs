@@ -0,0 +1,28 @@
+use crate::commands::Ack;
+use bytes::BytesMut;
+use masterpower_api::command::{Command, Request};
+
+/// MCHGC - Set max charging current
+pub struct MCHGC;
+
+impl Command for MCHGC {
+    const COMMAND: &'static str = "MCHGC";
+    type Request = ChargingCurrent;
+    type Response = Ack;
+}
+
+/// Charging current in amperes, shared with MUCHGC
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ChargingCurrent(pub u16);
+
+impl ChargingCurrent {
+    pub fn from_payload(payload: &str) -> Option<Self> {
+        payload.trim().parse::<u16>().ok().filter(|current| *current < 1000).map(ChargingCurrent)
+    }
+}
+
+impl Request for ChargingCurrent {
+    fn encode(&self, dst: &mut BytesMut) {
+        dst.extend_from_slice(format!("{:03}", self.0).as_bytes());
+    }
+}
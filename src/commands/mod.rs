@@ -0,0 +1,40 @@
+use bytes::BytesMut;
+use masterpower_api::command::Response;
+use masterpower_api::error::Error;
+use serde_derive::Serialize;
+use std::io::ErrorKind;
+
+pub mod mchgc;
+pub mod muchgc;
+pub mod pbcv;
+pub mod pbdv;
+pub mod pbft;
+pub mod pbt;
+pub mod pcp;
+pub mod pcvv;
+pub mod pd;
+pub mod pe;
+pub mod pgr;
+pub mod pop;
+pub mod psdv;
+pub mod qflag;
+pub mod qmchgcr;
+pub mod qmuchgcr;
+pub mod qpgs;
+
+/// Response to every setter command, the inverter only answers `(ACK` or `(NAK`
+#[derive(Debug, Serialize, Clone, Copy, Eq, PartialEq)]
+pub enum Ack {
+    Ack,
+    Nak,
+}
+
+impl Response for Ack {
+    fn decode(src: &mut BytesMut) -> Result<Self, Error> {
+        match &src[..] {
+            b"ACK" => Ok(Ack::Ack),
+            b"NAK" => Ok(Ack::Nak),
+            _ => Err(std::io::Error::new(ErrorKind::InvalidData, format!("Unexpected setter response: {}", String::from_utf8_lossy(&src[..]))).into()),
+        }
+    }
+}
@@ -0,0 +1,12 @@
+use crate::commands::mchgc::ChargingCurrent;
+use crate::commands::Ack;
+use masterpower_api::command::Command;
+
+/// MUCHGC - Set max utility charging current
+pub struct MUCHGC;
+
+impl Command for MUCHGC {
+    const COMMAND: &'static str = "MUCHGC";
+    type Request = ChargingCurrent;
+    type Response = Ack;
+}
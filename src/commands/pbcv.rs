@@ -0,0 +1,28 @@
+use crate::commands::Ack;
+use bytes::BytesMut;
+use masterpower_api::command::{Command, Request};
+
+/// PBCV - Set battery re-charge voltage
+pub struct PBCV;
+
+impl Command for PBCV {
+    const COMMAND: &'static str = "PBCV";
+    type Request = BatteryVoltage;
+    type Response = Ack;
+}
+
+/// Battery voltage with one decimal, shared with PBDV, PSDV, PCVV and PBFT
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatteryVoltage(pub f32);
+
+impl BatteryVoltage {
+    pub fn from_payload(payload: &str) -> Option<Self> {
+        payload.trim().parse::<f32>().ok().filter(|voltage| *voltage >= 0.0 && *voltage < 100.0).map(BatteryVoltage)
+    }
+}
+
+impl Request for BatteryVoltage {
+    fn encode(&self, dst: &mut BytesMut) {
+        dst.extend_from_slice(format!("{:04.1}", self.0).as_bytes());
+    }
+}
@@ -0,0 +1,12 @@
+use crate::commands::pbcv::BatteryVoltage;
+use crate::commands::Ack;
+use masterpower_api::command::Command;
+
+/// PBDV - Set battery re-discharge voltage
+pub struct PBDV;
+
+impl Command for PBDV {
+    const COMMAND: &'static str = "PBDV";
+    type Request = BatteryVoltage;
+    type Response = Ack;
+}
@@ -0,0 +1,12 @@
+use crate::commands::pbcv::BatteryVoltage;
+use crate::commands::Ack;
+use masterpower_api::command::Command;
+
+/// PBFT - Set battery float charging voltage
+pub struct PBFT;
+
+impl Command for PBFT {
+    const COMMAND: &'static str = "PBFT";
+    type Request = BatteryVoltage;
+    type Response = Ack;
+}
@@ -0,0 +1,61 @@
+use crate::commands::Ack;
+use bytes::BytesMut;
+use masterpower_api::command::{Command, Request};
+use masterpower_api::commands::qpiri;
+
+/// PBT - Set battery type
+pub struct PBT;
+
+impl Command for PBT {
+    const COMMAND: &'static str = "PBT";
+    type Request = BatteryType;
+    type Response = Ack;
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BatteryType {
+    AGM,
+    Flooded,
+    User,
+}
+
+impl BatteryType {
+    pub const OPTIONS: [&'static str; 3] = ["agm", "flooded", "user"];
+
+    pub fn from_payload(payload: &str) -> Option<Self> {
+        match payload.trim().to_ascii_lowercase().as_str() {
+            "agm" => Some(BatteryType::AGM),
+            "flooded" => Some(BatteryType::Flooded),
+            "user" => Some(BatteryType::User),
+            _ => None,
+        }
+    }
+
+    pub fn as_payload(self) -> &'static str {
+        match self {
+            BatteryType::AGM => "agm",
+            BatteryType::Flooded => "flooded",
+            BatteryType::User => "user",
+        }
+    }
+}
+
+impl From<&qpiri::BatteryType> for BatteryType {
+    fn from(battery_type: &qpiri::BatteryType) -> Self {
+        match battery_type {
+            qpiri::BatteryType::AGM => BatteryType::AGM,
+            qpiri::BatteryType::Flooded => BatteryType::Flooded,
+            qpiri::BatteryType::User => BatteryType::User,
+        }
+    }
+}
+
+impl Request for BatteryType {
+    fn encode(&self, dst: &mut BytesMut) {
+        dst.extend_from_slice(match self {
+            BatteryType::AGM => b"00",
+            BatteryType::Flooded => b"01",
+            BatteryType::User => b"02",
+        });
+    }
+}
@@ -0,0 +1,75 @@
+use crate::commands::Ack;
+use bytes::BytesMut;
+use masterpower_api::command::{Command, Request};
+use masterpower_api::commands::qpiri::{ChargeSourcePriority, MachineType};
+
+/// PCP - Set charger source priority
+pub struct PCP;
+
+impl Command for PCP {
+    const COMMAND: &'static str = "PCP";
+    type Request = ChargerSourcePriority;
+    type Response = Ack;
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ChargerSourcePriority {
+    UtilityFirst,
+    SolarFirst,
+    SolarAndUtility,
+    OnlySolar,
+}
+
+impl ChargerSourcePriority {
+    pub const OPTIONS: [&'static str; 4] = ["utility", "solar", "solar_utility", "solar_only"];
+
+    pub fn from_payload(payload: &str) -> Option<Self> {
+        match payload.trim().to_ascii_lowercase().as_str() {
+            "utility" => Some(ChargerSourcePriority::UtilityFirst),
+            "solar" => Some(ChargerSourcePriority::SolarFirst),
+            "solar_utility" => Some(ChargerSourcePriority::SolarAndUtility),
+            "solar_only" => Some(ChargerSourcePriority::OnlySolar),
+            _ => None,
+        }
+    }
+
+    /// The protocol only defines a charger for off grid and hybrid machines (QPIRI machine type 01 and 10),
+    /// grid tie machines (00) have no battery to charge
+    pub fn is_supported(machine_type: &MachineType) -> bool {
+        match machine_type {
+            MachineType::GridTie => false,
+            MachineType::OffGrid | MachineType::Hybrid => true,
+        }
+    }
+
+    pub fn as_payload(self) -> &'static str {
+        match self {
+            ChargerSourcePriority::UtilityFirst => "utility",
+            ChargerSourcePriority::SolarFirst => "solar",
+            ChargerSourcePriority::SolarAndUtility => "solar_utility",
+            ChargerSourcePriority::OnlySolar => "solar_only",
+        }
+    }
+}
+
+impl From<&ChargeSourcePriority> for ChargerSourcePriority {
+    fn from(priority: &ChargeSourcePriority) -> Self {
+        match priority {
+            ChargeSourcePriority::UtilityFirst => ChargerSourcePriority::UtilityFirst,
+            ChargeSourcePriority::SolarFirst => ChargerSourcePriority::SolarFirst,
+            ChargeSourcePriority::SolarAndUtility => ChargerSourcePriority::SolarAndUtility,
+            ChargeSourcePriority::OnlySolarCharging => ChargerSourcePriority::OnlySolar,
+        }
+    }
+}
+
+impl Request for ChargerSourcePriority {
+    fn encode(&self, dst: &mut BytesMut) {
+        dst.extend_from_slice(match self {
+            ChargerSourcePriority::UtilityFirst => b"00",
+            ChargerSourcePriority::SolarFirst => b"01",
+            ChargerSourcePriority::SolarAndUtility => b"02",
+            ChargerSourcePriority::OnlySolar => b"03",
+        });
+    }
+}
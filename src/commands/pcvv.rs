@@ -0,0 +1,12 @@
+use crate::commands::pbcv::BatteryVoltage;
+use crate::commands::Ack;
+use masterpower_api::command::Command;
+
+/// PCVV - Set battery bulk (C.V.) charging voltage
+pub struct PCVV;
+
+impl Command for PCVV {
+    const COMMAND: &'static str = "PCVV";
+    type Request = BatteryVoltage;
+    type Response = Ack;
+}
@@ -0,0 +1,12 @@
+use crate::commands::pe::DeviceFlag;
+use crate::commands::Ack;
+use masterpower_api::command::Command;
+
+/// PD - Disable device flag
+pub struct PD;
+
+impl Command for PD {
+    const COMMAND: &'static str = "PD";
+    type Request = DeviceFlag;
+    type Response = Ack;
+}
@@ -0,0 +1,83 @@
+use crate::commands::Ack;
+use bytes::BytesMut;
+use masterpower_api::command::{Command, Request};
+
+/// PE - Enable device flag
+pub struct PE;
+
+impl Command for PE {
+    const COMMAND: &'static str = "PE";
+    type Request = DeviceFlag;
+    type Response = Ack;
+}
+
+/// Feature flags toggled with PE / PD and reported by QFLAG
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DeviceFlag {
+    Buzzer,
+    OverloadBypass,
+    PowerSaving,
+    LcdEscape,
+    OverloadRestart,
+    OverTemperatureRestart,
+    Backlight,
+    PrimarySourceInterruptAlarm,
+    FaultCodeRecord,
+}
+
+impl DeviceFlag {
+    pub const ALL: [DeviceFlag; 9] = [
+        DeviceFlag::Buzzer,
+        DeviceFlag::OverloadBypass,
+        DeviceFlag::PowerSaving,
+        DeviceFlag::LcdEscape,
+        DeviceFlag::OverloadRestart,
+        DeviceFlag::OverTemperatureRestart,
+        DeviceFlag::Backlight,
+        DeviceFlag::PrimarySourceInterruptAlarm,
+        DeviceFlag::FaultCodeRecord,
+    ];
+
+    pub fn from_letter(letter: char) -> Option<Self> {
+        DeviceFlag::ALL.iter().copied().find(|flag| flag.letter() == letter)
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        DeviceFlag::ALL.iter().copied().find(|flag| flag.name() == name)
+    }
+
+    pub fn letter(self) -> char {
+        match self {
+            DeviceFlag::Buzzer => 'a',
+            DeviceFlag::OverloadBypass => 'b',
+            DeviceFlag::PowerSaving => 'j',
+            DeviceFlag::LcdEscape => 'k',
+            DeviceFlag::OverloadRestart => 'u',
+            DeviceFlag::OverTemperatureRestart => 'v',
+            DeviceFlag::Backlight => 'x',
+            DeviceFlag::PrimarySourceInterruptAlarm => 'y',
+            DeviceFlag::FaultCodeRecord => 'z',
+        }
+    }
+
+    /// Name used for the QFLAG json field and the set topic
+    pub fn name(self) -> &'static str {
+        match self {
+            DeviceFlag::Buzzer => "buzzer",
+            DeviceFlag::OverloadBypass => "overload_bypass",
+            DeviceFlag::PowerSaving => "power_saving",
+            DeviceFlag::LcdEscape => "lcd_escape",
+            DeviceFlag::OverloadRestart => "overload_restart",
+            DeviceFlag::OverTemperatureRestart => "over_temperature_restart",
+            DeviceFlag::Backlight => "backlight",
+            DeviceFlag::PrimarySourceInterruptAlarm => "primary_source_interrupt_alarm",
+            DeviceFlag::FaultCodeRecord => "fault_code_record",
+        }
+    }
+}
+
+impl Request for DeviceFlag {
+    fn encode(&self, dst: &mut BytesMut) {
+        dst.extend_from_slice(&[self.letter() as u8]);
+    }
+}
@@ -0,0 +1,56 @@
+use crate::commands::Ack;
+use bytes::BytesMut;
+use masterpower_api::command::{Command, Request};
+use masterpower_api::commands::qpiri;
+
+/// PGR - Set grid working range
+pub struct PGR;
+
+impl Command for PGR {
+    const COMMAND: &'static str = "PGR";
+    type Request = InputVoltageRange;
+    type Response = Ack;
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum InputVoltageRange {
+    Appliance,
+    UPS,
+}
+
+impl InputVoltageRange {
+    pub const OPTIONS: [&'static str; 2] = ["appliance", "ups"];
+
+    pub fn from_payload(payload: &str) -> Option<Self> {
+        match payload.trim().to_ascii_lowercase().as_str() {
+            "appliance" => Some(InputVoltageRange::Appliance),
+            "ups" => Some(InputVoltageRange::UPS),
+            _ => None,
+        }
+    }
+
+    pub fn as_payload(self) -> &'static str {
+        match self {
+            InputVoltageRange::Appliance => "appliance",
+            InputVoltageRange::UPS => "ups",
+        }
+    }
+}
+
+impl From<&qpiri::InputVoltageRange> for InputVoltageRange {
+    fn from(range: &qpiri::InputVoltageRange) -> Self {
+        match range {
+            qpiri::InputVoltageRange::Appliance => InputVoltageRange::Appliance,
+            qpiri::InputVoltageRange::UPS => InputVoltageRange::UPS,
+        }
+    }
+}
+
+impl Request for InputVoltageRange {
+    fn encode(&self, dst: &mut BytesMut) {
+        dst.extend_from_slice(match self {
+            InputVoltageRange::Appliance => b"00",
+            InputVoltageRange::UPS => b"01",
+        });
+    }
+}
@@ -0,0 +1,61 @@
+use crate::commands::Ack;
+use bytes::BytesMut;
+use masterpower_api::command::{Command, Request};
+use masterpower_api::commands::qpiri;
+
+/// POP - Set output source priority
+pub struct POP;
+
+impl Command for POP {
+    const COMMAND: &'static str = "POP";
+    type Request = OutputSourcePriority;
+    type Response = Ack;
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum OutputSourcePriority {
+    Utility,
+    Solar,
+    SBU,
+}
+
+impl OutputSourcePriority {
+    pub const OPTIONS: [&'static str; 3] = ["utility", "solar", "sbu"];
+
+    pub fn from_payload(payload: &str) -> Option<Self> {
+        match payload.trim().to_ascii_lowercase().as_str() {
+            "utility" => Some(OutputSourcePriority::Utility),
+            "solar" => Some(OutputSourcePriority::Solar),
+            "sbu" => Some(OutputSourcePriority::SBU),
+            _ => None,
+        }
+    }
+
+    pub fn as_payload(self) -> &'static str {
+        match self {
+            OutputSourcePriority::Utility => "utility",
+            OutputSourcePriority::Solar => "solar",
+            OutputSourcePriority::SBU => "sbu",
+        }
+    }
+}
+
+impl From<&qpiri::OutputSourcePriority> for OutputSourcePriority {
+    fn from(priority: &qpiri::OutputSourcePriority) -> Self {
+        match priority {
+            qpiri::OutputSourcePriority::UtilityFirst => OutputSourcePriority::Utility,
+            qpiri::OutputSourcePriority::SolarFirst => OutputSourcePriority::Solar,
+            qpiri::OutputSourcePriority::SBUFirst => OutputSourcePriority::SBU,
+        }
+    }
+}
+
+impl Request for OutputSourcePriority {
+    fn encode(&self, dst: &mut BytesMut) {
+        dst.extend_from_slice(match self {
+            OutputSourcePriority::Utility => b"00",
+            OutputSourcePriority::Solar => b"01",
+            OutputSourcePriority::SBU => b"02",
+        });
+    }
+}
@@ -0,0 +1,12 @@
+use crate::commands::pbcv::BatteryVoltage;
+use crate::commands::Ack;
+use masterpower_api::command::Command;
+
+/// PSDV - Set battery cut-off voltage
+pub struct PSDV;
+
+impl Command for PSDV {
+    const COMMAND: &'static str = "PSDV";
+    type Request = BatteryVoltage;
+    type Response = Ack;
+}
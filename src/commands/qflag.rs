@@ -0,0 +1,80 @@
+use crate::commands::pe::DeviceFlag;
+use bytes::BytesMut;
+use masterpower_api::command::{Command, Response};
+use masterpower_api::error::Error;
+use serde_derive::Serialize;
+use std::io::ErrorKind;
+
+/// QFLAG - Device flag status inquiry
+pub struct QFLAG;
+
+impl Command for QFLAG {
+    const COMMAND: &'static str = "QFLAG";
+    type Request = ();
+    type Response = DeviceFlags;
+}
+
+#[derive(Debug, Serialize, Default, Clone, Eq, PartialEq)]
+pub struct DeviceFlags {
+    pub buzzer: bool,
+    pub overload_bypass: bool,
+    pub power_saving: bool,
+    pub lcd_escape: bool,
+    pub overload_restart: bool,
+    pub over_temperature_restart: bool,
+    pub backlight: bool,
+    pub primary_source_interrupt_alarm: bool,
+    pub fault_code_record: bool,
+}
+
+impl DeviceFlags {
+    pub fn get(&self, flag: DeviceFlag) -> bool {
+        match flag {
+            DeviceFlag::Buzzer => self.buzzer,
+            DeviceFlag::OverloadBypass => self.overload_bypass,
+            DeviceFlag::PowerSaving => self.power_saving,
+            DeviceFlag::LcdEscape => self.lcd_escape,
+            DeviceFlag::OverloadRestart => self.overload_restart,
+            DeviceFlag::OverTemperatureRestart => self.over_temperature_restart,
+            DeviceFlag::Backlight => self.backlight,
+            DeviceFlag::PrimarySourceInterruptAlarm => self.primary_source_interrupt_alarm,
+            DeviceFlag::FaultCodeRecord => self.fault_code_record,
+        }
+    }
+
+    fn set(&mut self, flag: DeviceFlag, enabled: bool) {
+        let field = match flag {
+            DeviceFlag::Buzzer => &mut self.buzzer,
+            DeviceFlag::OverloadBypass => &mut self.overload_bypass,
+            DeviceFlag::PowerSaving => &mut self.power_saving,
+            DeviceFlag::LcdEscape => &mut self.lcd_escape,
+            DeviceFlag::OverloadRestart => &mut self.overload_restart,
+            DeviceFlag::OverTemperatureRestart => &mut self.over_temperature_restart,
+            DeviceFlag::Backlight => &mut self.backlight,
+            DeviceFlag::PrimarySourceInterruptAlarm => &mut self.primary_source_interrupt_alarm,
+            DeviceFlag::FaultCodeRecord => &mut self.fault_code_record,
+        };
+        *field = enabled;
+    }
+}
+
+impl Response for DeviceFlags {
+    // Response looks like `EakxyzDbjuv`, letters after E are enabled and letters after D disabled
+    fn decode(src: &mut BytesMut) -> Result<Self, Error> {
+        let mut flags = DeviceFlags::default();
+        let mut enabled = None;
+        for letter in String::from_utf8_lossy(&src[..]).chars() {
+            match (letter, enabled) {
+                ('E', _) => enabled = Some(true),
+                ('D', _) => enabled = Some(false),
+                (letter, Some(enabled)) => {
+                    if let Some(flag) = DeviceFlag::from_letter(letter) {
+                        flags.set(flag, enabled);
+                    }
+                }
+                (letter, None) => return Err(std::io::Error::new(ErrorKind::InvalidData, format!("Unexpected device flag: {}", letter)).into()),
+            }
+        }
+        Ok(flags)
+    }
+}
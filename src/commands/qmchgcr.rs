@@ -0,0 +1,35 @@
+use bytes::BytesMut;
+use masterpower_api::command::{Command, Response};
+use masterpower_api::error::Error;
+use serde_derive::Serialize;
+use std::io::ErrorKind;
+
+/// QMCHGCR - Selectable values of max charging current inquiry
+pub struct QMCHGCR;
+
+impl Command for QMCHGCR {
+    const COMMAND: &'static str = "QMCHGCR";
+    type Request = ();
+    type Response = ChargingCurrents;
+}
+
+#[derive(Debug, Serialize, Clone, Eq, PartialEq)]
+pub struct ChargingCurrents {
+    pub currents: Vec<u16>,
+}
+
+impl ChargingCurrents {
+    pub fn contains(&self, current: u16) -> bool {
+        self.currents.contains(&current)
+    }
+}
+
+impl Response for ChargingCurrents {
+    fn decode(src: &mut BytesMut) -> Result<Self, Error> {
+        let currents = String::from_utf8_lossy(&src[..])
+            .split_whitespace()
+            .map(|current| current.parse::<u16>().map_err(|_| std::io::Error::new(ErrorKind::InvalidData, format!("Invalid charging current: {}", current))))
+            .collect::<Result<Vec<u16>, std::io::Error>>()?;
+        Ok(ChargingCurrents { currents })
+    }
+}
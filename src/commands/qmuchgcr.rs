@@ -0,0 +1,11 @@
+use crate::commands::qmchgcr::ChargingCurrents;
+use masterpower_api::command::Command;
+
+/// QMUCHGCR - Selectable values of max utility charging current inquiry
+pub struct QMUCHGCR;
+
+impl Command for QMUCHGCR {
+    const COMMAND: &'static str = "QMUCHGCR";
+    type Request = ();
+    type Response = ChargingCurrents;
+}
@@ -0,0 +1,351 @@
+use crate::commands::pcp::ChargerSourcePriority;
+use bytes::BytesMut;
+use masterpower_api::command::{Command, Request, Response};
+use masterpower_api::error::Error;
+use serde_derive::Serialize;
+use std::io::ErrorKind;
+use std::str::FromStr;
+
+/// QPGSn - Parallel information inquiry of the n-th unit in a parallel group
+pub struct QPGS;
+
+impl Command for QPGS {
+    const COMMAND: &'static str = "QPGS";
+    type Request = ParallelUnit;
+    type Response = ParallelStatus;
+}
+
+/// Index of a unit in the parallel group, starting at 0
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ParallelUnit(pub u8);
+
+impl Request for ParallelUnit {
+    fn encode(&self, dst: &mut BytesMut) {
+        dst.extend_from_slice(self.0.to_string().as_bytes());
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ParallelMode {
+    PowerOn,
+    Standby,
+    Line,
+    Battery,
+    Fault,
+    PowerSaving,
+    Shutdown,
+}
+
+/// Phase the unit feeds, three phase and split phase systems have one or more units per phase
+#[derive(Debug, Serialize, Clone, Copy, Eq, PartialEq)]
+pub enum OutputPhase {
+    #[serde(rename = "single")]
+    Single,
+    #[serde(rename = "parallel")]
+    Parallel,
+    #[serde(rename = "phase_1_of_3")]
+    Phase1Of3,
+    #[serde(rename = "phase_2_of_3")]
+    Phase2Of3,
+    #[serde(rename = "phase_3_of_3")]
+    Phase3Of3,
+    #[serde(rename = "phase_1_of_2")]
+    Phase1Of2,
+    #[serde(rename = "phase_2_of_2_120")]
+    Phase2Of2At120,
+    #[serde(rename = "phase_2_of_2_180")]
+    Phase2Of2At180,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BatteryStatus {
+    Normal,
+    Under,
+    Open,
+}
+
+#[derive(Debug, Serialize, Clone, Eq, PartialEq)]
+pub struct ParallelUnitStatus {
+    pub scc_ok: bool,
+    pub ac_charging: bool,
+    pub scc_charging: bool,
+    pub battery_status: BatteryStatus,
+    pub line_loss: bool,
+    pub load_on: bool,
+    pub configuration_changed: bool,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct ParallelStatus {
+    pub parallel_exists: bool,
+    pub serial_number: String,
+    pub mode: ParallelMode,
+    pub fault_code: u8,
+    pub grid_voltage: f32,
+    pub grid_frequency: f32,
+    pub ac_out_voltage: f32,
+    pub ac_out_frequency: f32,
+    pub ac_out_apparent_power: u32,
+    pub ac_out_active_power: u32,
+    pub out_load_percent: u32,
+    pub battery_voltage: f32,
+    pub battery_charge_current: u32,
+    pub battery_capacity: u32,
+    pub pv_input_voltage: f32,
+    pub total_charging_current: u32,
+    pub total_ac_out_apparent_power: u32,
+    pub total_ac_out_active_power: u32,
+    pub total_out_load_percent: u32,
+    pub status: ParallelUnitStatus,
+    pub phase: OutputPhase,
+    pub charger_source_priority: &'static str,
+    pub max_charging_current: u32,
+    pub max_charging_current_range: u32,
+    pub max_ac_charging_current: u32,
+    pub pv_input_current: u32,
+    pub battery_discharge_current: u32,
+}
+
+impl Response for ParallelStatus {
+    // Response looks like `1 92931701100510 B 00 000.0 00.00 230.0 50.00 0989 0907 019 51.5 000 069 000.0 000 01012 00944 007 00000010 1 1 060 080 30 00 008`,
+    // older firmwares leave out the currents after the charger source priority
+    fn decode(src: &mut BytesMut) -> Result<Self, Error> {
+        let response = String::from_utf8_lossy(&src[..]).to_string();
+        let fields: Vec<&str> = response.split_whitespace().collect();
+        if fields.len() < 22 {
+            return Err(invalid(format!("Expected at least 22 parallel status fields, got {}", fields.len())));
+        }
+
+        let mode = match fields[2] {
+            "P" => ParallelMode::PowerOn,
+            "S" => ParallelMode::Standby,
+            "L" => ParallelMode::Line,
+            "B" => ParallelMode::Battery,
+            "F" => ParallelMode::Fault,
+            "H" => ParallelMode::PowerSaving,
+            "D" => ParallelMode::Shutdown,
+            mode => return Err(invalid(format!("Unexpected parallel mode: {}", mode))),
+        };
+
+        let bits: Vec<bool> = fields[19].chars().map(|bit| bit == '1').collect();
+        if bits.len() != 8 {
+            return Err(invalid(format!("Unexpected parallel inverter status: {}", fields[19])));
+        }
+        let status = ParallelUnitStatus {
+            scc_ok: bits[0],
+            ac_charging: bits[1],
+            scc_charging: bits[2],
+            battery_status: match (bits[3], bits[4]) {
+                (false, false) => BatteryStatus::Normal,
+                (false, true) => BatteryStatus::Under,
+                (true, false) => BatteryStatus::Open,
+                (true, true) => return Err(invalid(format!("Unexpected parallel battery status: {}", fields[19]))),
+            },
+            line_loss: bits[5],
+            load_on: bits[6],
+            configuration_changed: bits[7],
+        };
+
+        let phase = match fields[20] {
+            "0" => OutputPhase::Single,
+            "1" => OutputPhase::Parallel,
+            "2" => OutputPhase::Phase1Of3,
+            "3" => OutputPhase::Phase2Of3,
+            "4" => OutputPhase::Phase3Of3,
+            "5" => OutputPhase::Phase1Of2,
+            "6" => OutputPhase::Phase2Of2At120,
+            "7" => OutputPhase::Phase2Of2At180,
+            phase => return Err(invalid(format!("Unexpected parallel output mode: {}", phase))),
+        };
+
+        let charger_source_priority = match fields[21] {
+            "0" => ChargerSourcePriority::UtilityFirst,
+            "1" => ChargerSourcePriority::SolarFirst,
+            "2" => ChargerSourcePriority::SolarAndUtility,
+            "3" => ChargerSourcePriority::OnlySolar,
+            priority => return Err(invalid(format!("Unexpected parallel charger source priority: {}", priority))),
+        };
+
+        Ok(ParallelStatus {
+            parallel_exists: fields[0] == "1",
+            serial_number: fields[1].to_string(),
+            mode,
+            fault_code: parse(&fields, 3)?,
+            grid_voltage: parse(&fields, 4)?,
+            grid_frequency: parse(&fields, 5)?,
+            ac_out_voltage: parse(&fields, 6)?,
+            ac_out_frequency: parse(&fields, 7)?,
+            ac_out_apparent_power: parse(&fields, 8)?,
+            ac_out_active_power: parse(&fields, 9)?,
+            out_load_percent: parse(&fields, 10)?,
+            battery_voltage: parse(&fields, 11)?,
+            battery_charge_current: parse(&fields, 12)?,
+            battery_capacity: parse(&fields, 13)?,
+            pv_input_voltage: parse(&fields, 14)?,
+            total_charging_current: parse(&fields, 15)?,
+            total_ac_out_apparent_power: parse(&fields, 16)?,
+            total_ac_out_active_power: parse(&fields, 17)?,
+            total_out_load_percent: parse(&fields, 18)?,
+            status,
+            phase,
+            charger_source_priority: charger_source_priority.as_payload(),
+            max_charging_current: parse_optional(&fields, 22)?,
+            max_charging_current_range: parse_optional(&fields, 23)?,
+            max_ac_charging_current: parse_optional(&fields, 24)?,
+            pv_input_current: parse_optional(&fields, 25)?,
+            battery_discharge_current: parse_optional(&fields, 26)?,
+        })
+    }
+}
+
+/// Sums of the units that answered and are part of the parallel group, the load is their average
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct ParallelTotals {
+    pub units: u32,
+    pub ac_out_apparent_power: u32,
+    pub ac_out_active_power: u32,
+    pub out_load_percent: u32,
+    pub battery_charge_current: u32,
+    pub battery_discharge_current: u32,
+    pub pv_input_current: u32,
+    pub pv_input_power: u32,
+}
+
+impl ParallelTotals {
+    pub fn new<'a, I: IntoIterator<Item = &'a ParallelStatus>>(units: I) -> Self {
+        let units: Vec<&ParallelStatus> = units.into_iter().filter(|unit| unit.parallel_exists).collect();
+        let count = units.len() as u32;
+        ParallelTotals {
+            units: count,
+            ac_out_apparent_power: units.iter().map(|unit| unit.ac_out_apparent_power).sum(),
+            ac_out_active_power: units.iter().map(|unit| unit.ac_out_active_power).sum(),
+            out_load_percent: units.iter().map(|unit| unit.out_load_percent).sum::<u32>().checked_div(count).unwrap_or(0),
+            battery_charge_current: units.iter().map(|unit| unit.battery_charge_current).sum(),
+            battery_discharge_current: units.iter().map(|unit| unit.battery_discharge_current).sum(),
+            pv_input_current: units.iter().map(|unit| unit.pv_input_current).sum(),
+            pv_input_power: units.iter().map(|unit| (unit.pv_input_voltage * unit.pv_input_current as f32).round() as u32).sum(),
+        }
+    }
+}
+
+fn parse<T: FromStr>(fields: &[&str], index: usize) -> Result<T, Error> {
+    fields[index].parse().map_err(|_| invalid(format!("Unexpected parallel status field {}: {}", index, fields[index])))
+}
+
+fn parse_optional<T: FromStr + Default>(fields: &[&str], index: usize) -> Result<T, Error> {
+    if index < fields.len() {
+        parse(fields, index)
+    } else {
+        Ok(T::default())
+    }
+}
+
+fn invalid(message: String) -> Error {
+    std::io::Error::new(ErrorKind::InvalidData, message).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "1 92931701100510 B 00 000.0 00.00 230.0 50.00 0989 0907 019 51.5 000 069 000.0 000 01012 00944 007 00000010 1 1 060 080 30 00 008";
+
+    fn decode(response: &str) -> Result<ParallelStatus, Error> {
+        ParallelStatus::decode(&mut BytesMut::from(response))
+    }
+
+    /// Replaces one whitespace separated field of the sample
+    fn with_field(index: usize, value: &str) -> String {
+        let mut fields: Vec<&str> = SAMPLE.split_whitespace().collect();
+        fields[index] = value;
+        fields.join(" ")
+    }
+
+    #[test]
+    fn decodes_full_response() {
+        let status = decode(SAMPLE).unwrap();
+        assert_eq!(
+            status,
+            ParallelStatus {
+                parallel_exists: true,
+                serial_number: "92931701100510".to_string(),
+                mode: ParallelMode::Battery,
+                fault_code: 0,
+                grid_voltage: 0.0,
+                grid_frequency: 0.0,
+                ac_out_voltage: 230.0,
+                ac_out_frequency: 50.0,
+                ac_out_apparent_power: 989,
+                ac_out_active_power: 907,
+                out_load_percent: 19,
+                battery_voltage: 51.5,
+                battery_charge_current: 0,
+                battery_capacity: 69,
+                pv_input_voltage: 0.0,
+                total_charging_current: 0,
+                total_ac_out_apparent_power: 1012,
+                total_ac_out_active_power: 944,
+                total_out_load_percent: 7,
+                status: ParallelUnitStatus {
+                    scc_ok: false,
+                    ac_charging: false,
+                    scc_charging: false,
+                    battery_status: BatteryStatus::Normal,
+                    line_loss: false,
+                    load_on: true,
+                    configuration_changed: false,
+                },
+                phase: OutputPhase::Parallel,
+                charger_source_priority: "solar",
+                max_charging_current: 60,
+                max_charging_current_range: 80,
+                max_ac_charging_current: 30,
+                pv_input_current: 0,
+                battery_discharge_current: 8,
+            }
+        );
+    }
+
+    #[test]
+    fn defaults_fields_missing_on_older_firmwares() {
+        let fields: Vec<&str> = SAMPLE.split_whitespace().take(22).collect();
+        let status = decode(&fields.join(" ")).unwrap();
+        assert_eq!(status.charger_source_priority, "solar");
+        assert_eq!(status.max_charging_current, 0);
+        assert_eq!(status.max_charging_current_range, 0);
+        assert_eq!(status.max_ac_charging_current, 0);
+        assert_eq!(status.pv_input_current, 0);
+        assert_eq!(status.battery_discharge_current, 0);
+    }
+
+    #[test]
+    fn rejects_truncated_response() {
+        let fields: Vec<&str> = SAMPLE.split_whitespace().take(21).collect();
+        assert!(decode(&fields.join(" ")).is_err());
+    }
+
+    #[test]
+    fn decodes_battery_status_bits() {
+        assert_eq!(decode(&with_field(19, "00000010")).unwrap().status.battery_status, BatteryStatus::Normal);
+        assert_eq!(decode(&with_field(19, "00001010")).unwrap().status.battery_status, BatteryStatus::Under);
+        assert_eq!(decode(&with_field(19, "00010010")).unwrap().status.battery_status, BatteryStatus::Open);
+        assert!(decode(&with_field(19, "00011010")).is_err());
+    }
+
+    #[test]
+    fn decodes_status_flags() {
+        let status = decode(&with_field(19, "11100101")).unwrap().status;
+        assert!(status.scc_ok && status.ac_charging && status.scc_charging);
+        assert!(status.line_loss && !status.load_on && status.configuration_changed);
+    }
+
+    #[test]
+    fn rejects_unknown_mode_and_phase() {
+        assert!(decode(&with_field(2, "X")).is_err());
+        assert!(decode(&with_field(20, "8")).is_err());
+        assert!(decode(&with_field(21, "4")).is_err());
+        assert!(decode(&with_field(19, "0000001")).is_err());
+    }
+}
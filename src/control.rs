@@ -0,0 +1,291 @@
+use crate::commands::mchgc::{ChargingCurrent, MCHGC};
+use crate::commands::muchgc::MUCHGC;
+use crate::commands::pbcv::{BatteryVoltage, PBCV};
+use crate::commands::pbdv::PBDV;
+use crate::commands::pbft::PBFT;
+use crate::commands::pbt::{BatteryType, PBT};
+use crate::commands::pcp::{ChargerSourcePriority, PCP};
+use crate::commands::pcvv::PCVV;
+use crate::commands::pd::PD;
+use crate::commands::pe::{DeviceFlag, PE};
+use crate::commands::pgr::{InputVoltageRange, PGR};
+use crate::commands::pop::{OutputSourcePriority, POP};
+use crate::commands::psdv::PSDV;
+use crate::commands::qflag::QFLAG;
+use crate::commands::qmchgcr::QMCHGCR;
+use crate::commands::qmuchgcr::QMUCHGCR;
+use crate::commands::Ack;
+use crate::mqtt::SharedClient;
+use crate::settings::{Device, MqttSettings};
+use crate::transport::InverterStream;
+use crate::{publish_error, publish_update};
+
+use masterpower_api::command::Command;
+use masterpower_api::commands::qpiri::QPIRI;
+use masterpower_api::inverter::Inverter;
+
+use log::{debug, info, warn};
+use mqtt_async_client::client::{Publish as PublishOpts, QoS, Subscribe, SubscribeTopic};
+use serde_derive::Serialize;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::time::{delay_for, Duration};
+
+/// Settings that can be changed through `<topic>/set/<setting>`, device flags are added on top
+const CONTROL_SETTINGS: [&str; 11] = [
+    "output_source_priority",
+    "charger_source_priority",
+    "battery_type",
+    "input_voltage_range",
+    "max_charging_current",
+    "max_ac_charging_current",
+    "battery_recharge_voltage",
+    "battery_redischarge_voltage",
+    "battery_under_voltage",
+    "battery_bulk_voltage",
+    "battery_float_voltage",
+];
+
+/// Setting change received on `<topic>/set/<setting>`
+#[derive(Debug)]
+pub struct ControlMessage {
+    setting: String,
+    payload: String,
+}
+
+/// Select settings in the same format accepted by the set topics, derived from QPIRI
+#[derive(Serialize, Debug)]
+struct SettingsState {
+    output_source_priority: &'static str,
+    charger_source_priority: &'static str,
+    battery_type: &'static str,
+    input_voltage_range: &'static str,
+    max_charging_current: String,
+    max_ac_charging_current: String,
+}
+
+/// Publishes QPIRI along with the `settings` topic used by the select entities
+pub async fn publish_qpiri(mqtt_client: &SharedClient, mqtt: &MqttSettings, qpiri: &<QPIRI as Command>::Response) -> Result<(), Box<dyn std::error::Error>> {
+    publish_update(mqtt_client, mqtt, "qpiri", serde_json::to_string(qpiri)?).await?;
+
+    let state = SettingsState {
+        output_source_priority: OutputSourcePriority::from(&qpiri.output_source_priority).as_payload(),
+        charger_source_priority: ChargerSourcePriority::from(&qpiri.charge_source_priority).as_payload(),
+        battery_type: BatteryType::from(&qpiri.battery_type).as_payload(),
+        input_voltage_range: InputVoltageRange::from(&qpiri.input_voltage_range).as_payload(),
+        max_charging_current: qpiri.max_charging_current.to_string(),
+        max_ac_charging_current: qpiri.max_ac_charging_current.to_string(),
+    };
+    publish_update(mqtt_client, mqtt, "settings", serde_json::to_string(&state)?).await?;
+    Ok(())
+}
+
+pub async fn subscribe_control_topics(mqtt_client: &SharedClient, mqtt: &MqttSettings) -> Result<(), Box<dyn std::error::Error>> {
+    let topics = CONTROL_SETTINGS
+        .iter()
+        .copied()
+        .chain(DeviceFlag::ALL.iter().map(|flag| flag.name()))
+        .map(|setting| SubscribeTopic {
+            topic_path: format!("{}/set/{}", mqtt.topic, setting),
+            qos: QoS::AtLeastOnce,
+        })
+        .collect();
+    mqtt_client.subscribe(Subscribe::new(topics)).await?;
+    info!("Subscribed to control topics");
+    Ok(())
+}
+
+/// Reads the control topics of every device and forwards each message to its device, never returns
+pub async fn route_control_messages(mqtt_client: &SharedClient, routes: &[(String, UnboundedSender<ControlMessage>)]) {
+    loop {
+        match mqtt_client.read_subscriptions(Duration::from_millis(10)).await {
+            Some(Ok(read)) => {
+                let route = routes.iter().find_map(|(topic, sender)| read.topic().strip_prefix(&format!("{}/set/", topic)).map(|setting| (setting.to_string(), sender)));
+                if let Some((setting, sender)) = route {
+                    let payload = String::from_utf8_lossy(read.payload()).to_string();
+                    debug!("Received control message {} = {} on {}", setting, payload, read.topic());
+                    let _ = sender.send(ControlMessage { setting, payload });
+                }
+            }
+            Some(Err(error)) => {
+                warn!("Could not read control messages: {}", error);
+                delay_for(Duration::from_secs(1)).await;
+            }
+            // Give the inverters a chance to publish
+            None => delay_for(Duration::from_millis(100)).await,
+        }
+    }
+}
+
+pub async fn handle_control_messages(inverter: &mut Inverter<InverterStream>, mqtt_client: &SharedClient, device: &Device, messages: &mut UnboundedReceiver<ControlMessage>) -> Result<(), Box<dyn std::error::Error>> {
+    // Apply every pending command without blocking the update loop
+    while let Ok(ControlMessage { setting, payload }) = messages.try_recv() {
+        // Errors are turned into strings right away, they must not be kept across an await
+        let result = match apply_setting(inverter, &setting, &payload).await.map_err(|error| error.to_string()) {
+            Ok(()) => refresh_setting(inverter, mqtt_client, &device.mqtt, &setting, &payload).await.map_err(|error| error.to_string()),
+            Err(error) => Err(error),
+        };
+
+        match result {
+            Ok(()) => {
+                info!("Inverter accepted {} = {}", setting, payload);
+                publish_result(mqtt_client, &device.mqtt, &setting, "ACK").await?;
+            }
+            Err(error) => {
+                warn!("Could not set {} = {}: {}", setting, payload, error);
+                publish_result(mqtt_client, &device.mqtt, &setting, "NAK").await?;
+                publish_error(mqtt_client, &device.mqtt, format!("Could not set {}: {}", setting, error)).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn apply_setting(inverter: &mut Inverter<InverterStream>, setting: &str, payload: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let ack = match setting {
+        "output_source_priority" => {
+            let priority = OutputSourcePriority::from_payload(payload).ok_or_else(|| format!("Invalid output source priority '{}', expected utility, solar or sbu", payload))?;
+            inverter.execute::<POP>(priority).await?
+        }
+        "battery_type" => {
+            let battery_type = BatteryType::from_payload(payload).ok_or_else(|| format!("Invalid battery type '{}', expected agm, flooded or user", payload))?;
+            inverter.execute::<PBT>(battery_type).await?
+        }
+        "input_voltage_range" => {
+            let range = InputVoltageRange::from_payload(payload).ok_or_else(|| format!("Invalid input voltage range '{}', expected appliance or ups", payload))?;
+            inverter.execute::<PGR>(range).await?
+        }
+        "charger_source_priority" => {
+            let priority = ChargerSourcePriority::from_payload(payload).ok_or_else(|| format!("Invalid charger source priority '{}', expected utility, solar, solar_utility or solar_only", payload))?;
+            let qpiri = inverter.execute::<QPIRI>(()).await?;
+            if !ChargerSourcePriority::is_supported(&qpiri.machine_type) {
+                return Err(format!("Charger source priority can't be set on {:?} machines", qpiri.machine_type).into());
+            }
+            inverter.execute::<PCP>(priority).await?
+        }
+        "max_charging_current" => {
+            let current = ChargingCurrent::from_payload(payload).ok_or_else(|| format!("Invalid max charging current '{}'", payload))?;
+            let allowed = inverter.execute::<QMCHGCR>(()).await?;
+            if !allowed.contains(current.0) {
+                return Err(format!("Max charging current {}A is not one of {:?}", current.0, allowed.currents).into());
+            }
+            inverter.execute::<MCHGC>(current).await?
+        }
+        "max_ac_charging_current" => {
+            let current = ChargingCurrent::from_payload(payload).ok_or_else(|| format!("Invalid max AC charging current '{}'", payload))?;
+            let allowed = inverter.execute::<QMUCHGCR>(()).await?;
+            if !allowed.contains(current.0) {
+                return Err(format!("Max AC charging current {}A is not one of {:?}", current.0, allowed.currents).into());
+            }
+            inverter.execute::<MUCHGC>(current).await?
+        }
+        "battery_recharge_voltage" | "battery_redischarge_voltage" | "battery_under_voltage" | "battery_bulk_voltage" | "battery_float_voltage" => {
+            let voltage = BatteryVoltage::from_payload(payload).ok_or_else(|| format!("Invalid battery voltage '{}'", payload))?;
+            let qpiri = inverter.execute::<QPIRI>(()).await?;
+            check_battery_voltage(setting, voltage, &qpiri)?;
+            match setting {
+                "battery_recharge_voltage" => inverter.execute::<PBCV>(voltage).await?,
+                "battery_redischarge_voltage" => inverter.execute::<PBDV>(voltage).await?,
+                "battery_under_voltage" => inverter.execute::<PSDV>(voltage).await?,
+                "battery_bulk_voltage" => inverter.execute::<PCVV>(voltage).await?,
+                _ => inverter.execute::<PBFT>(voltage).await?,
+            }
+        }
+        _ => {
+            let flag = DeviceFlag::from_name(setting).ok_or_else(|| format!("Unknown setting '{}'", setting))?;
+            match payload.trim() {
+                "ON" => inverter.execute::<PE>(flag).await?,
+                "OFF" => inverter.execute::<PD>(flag).await?,
+                _ => return Err(format!("Invalid {} value '{}', expected ON or OFF", setting, payload).into()),
+            }
+        }
+    };
+
+    match ack {
+        Ack::Ack => Ok(()),
+        Ack::Nak => Err("Inverter rejected the command".into()),
+    }
+}
+
+/// Allowed range for a battery voltage setting, limits are given for a 48V system and scaled to the battery rating voltage
+pub fn battery_voltage_range(setting: &str, rating_voltage: f32) -> Result<(f32, f32), Box<dyn std::error::Error>> {
+    let scale = match rating_voltage as u8 {
+        12 => 0.25,
+        24 => 0.5,
+        48 => 1.0,
+        _ => return Err(format!("Unsupported battery rating voltage {}V", rating_voltage).into()),
+    };
+
+    let (min, max) = match setting {
+        "battery_recharge_voltage" => (44.0, 51.0),
+        "battery_redischarge_voltage" => (48.0, 58.0),
+        "battery_under_voltage" => (40.0, 48.0),
+        _ => (48.0, 58.4),
+    };
+
+    Ok((min * scale, max * scale))
+}
+
+fn check_battery_voltage(setting: &str, voltage: BatteryVoltage, qpiri: &<QPIRI as Command>::Response) -> Result<(), Box<dyn std::error::Error>> {
+    let rating = qpiri.battery_rating_voltage as f32;
+    let (min, max) = battery_voltage_range(setting, rating)?;
+
+    // Re-discharge voltage 0 means "battery fully charged"
+    if setting == "battery_redischarge_voltage" && voltage.0 == 0.0 {
+        return Ok(());
+    }
+
+    // Charging and cut-off voltages are fixed unless the battery type is user defined
+    let user_only = matches!(setting, "battery_under_voltage" | "battery_bulk_voltage" | "battery_float_voltage");
+    if user_only && BatteryType::from(&qpiri.battery_type) != BatteryType::User {
+        return Err(format!("{} can only be changed with the user battery type, current type is {:?}", setting, qpiri.battery_type).into());
+    }
+    if voltage.0 < min || voltage.0 > max {
+        return Err(format!("{}V is outside the {}V - {}V range for a {}V battery", voltage.0, min, max, rating).into());
+    }
+
+    Ok(())
+}
+
+/// Reads back the state affected by a setting so the new value shows up immediately
+async fn refresh_setting(inverter: &mut Inverter<InverterStream>, mqtt_client: &SharedClient, mqtt: &MqttSettings, setting: &str, payload: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(flag) = DeviceFlag::from_name(setting) {
+        let flags = inverter.execute::<QFLAG>(()).await?;
+        publish_update(mqtt_client, mqtt, "qflag", serde_json::to_string(&flags)?).await?;
+        if flags.get(flag) != (payload.trim() == "ON") {
+            return Err(format!("Inverter acknowledged {} = {} but did not apply it", setting, payload).into());
+        }
+        return Ok(());
+    }
+
+    let qpiri = inverter.execute::<QPIRI>(()).await?;
+    publish_qpiri(mqtt_client, mqtt, &qpiri).await?;
+    verify_setting(setting, payload, &qpiri)
+}
+
+/// Confirms the inverter actually applied a setting after acknowledging it
+fn verify_setting(setting: &str, payload: &str, qpiri: &<QPIRI as Command>::Response) -> Result<(), Box<dyn std::error::Error>> {
+    let applied = match setting {
+        "battery_recharge_voltage" => qpiri.battery_recharge_voltage as f32,
+        "battery_redischarge_voltage" => qpiri.battery_redischarge_voltage as f32,
+        "battery_under_voltage" => qpiri.battery_under_voltage as f32,
+        "battery_bulk_voltage" => qpiri.battery_bulk_voltage as f32,
+        "battery_float_voltage" => qpiri.battery_float_voltage as f32,
+        _ => return Ok(()),
+    };
+
+    let requested = BatteryVoltage::from_payload(payload).map(|voltage| voltage.0).unwrap_or_default();
+    if (applied - requested).abs() > 0.05 {
+        return Err(format!("Inverter acknowledged {}V but reports {}V", requested, applied).into());
+    }
+
+    Ok(())
+}
+
+async fn publish_result(mqtt_client: &SharedClient, mqtt: &MqttSettings, setting: &str, result: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut msg = PublishOpts::new(format!("{}/set/{}/result", mqtt.topic, setting).to_string(), Vec::from(result));
+    msg.set_qos(mqtt.topics.state.qos());
+    msg.set_retain(mqtt.topics.state.retain);
+    mqtt_client.publish(&msg).await?;
+    Ok(())
+}
@@ -1,9 +1,27 @@
 #![warn(clippy::all)]
 
+mod change_filter;
+mod cli;
+mod commands;
+mod control;
+mod mqtt;
 mod mqtt_discovery;
+mod scheduler;
 mod settings;
-use crate::mqtt_discovery::run_mqtt_discovery;
-use crate::settings::MqttSettings;
+mod tls;
+mod transport;
+use crate::change_filter::ChangeFilter;
+use crate::cli::Args;
+use crate::commands::qflag::QFLAG;
+use crate::commands::qmchgcr::QMCHGCR;
+use crate::commands::qmuchgcr::QMUCHGCR;
+use crate::commands::qpgs::{ParallelStatus, ParallelTotals, ParallelUnit, QPGS};
+use crate::control::{handle_control_messages, publish_qpiri, route_control_messages, subscribe_control_topics, ControlMessage};
+use crate::mqtt::SharedClient;
+use crate::mqtt_discovery::{run_control_discovery, run_mqtt_discovery, run_parallel_discovery};
+use crate::scheduler::{PolledCommand, Scheduler};
+use crate::settings::{Device, MqttSettings};
+use crate::transport::{is_device_present, open_inverter, InverterStream};
 use settings::Settings;
 
 use masterpower_api::commands::qid::QID;
@@ -16,139 +34,481 @@ use masterpower_api::commands::qvfw::QVFW;
 use masterpower_api::commands::qvfw2::QVFW2;
 use masterpower_api::inverter::Inverter;
 
-use libc::{open, O_RDWR};
-use log::{debug, error, info};
-use mqtt_async_client::client::{Client as MQTTClient, KeepAlive, Publish as PublishOpts, QoS};
-use std::os::unix::ffi::OsStrExt;
-use std::os::unix::io::FromRawFd;
-use std::path::Path;
-use std::thread::sleep;
+use futures::future::join_all;
+use libc::{EBADF, EIO, ENODEV, ENXIO};
+use log::{debug, error, info, warn};
+use mqtt_async_client::client::{KeepAlive, Publish as PublishOpts, QoS};
+use serde::Serialize;
+use serde_json::Value;
+use std::io::ErrorKind;
+use std::sync::Arc;
 use std::time::Instant;
-use tokio::fs::File;
-use tokio::time::Duration;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::sync::watch;
+use tokio::time::{delay_for, Duration};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Starting {} version {}", env!("CARGO_PKG_NAME").to_ascii_uppercase(), env!("CARGO_PKG_VERSION"));
+    let args = Args::parse();
+
+    // Keep stdout clean for the json printed by --once
+    if !args.once {
+        println!("Starting {} version {}", env!("CARGO_PKG_NAME").to_ascii_uppercase(), env!("CARGO_PKG_VERSION"));
+    }
 
     // Load configuration
-    let settings = Settings::new();
+    let settings = Settings::new(&args.config);
     if let Err(e) = settings {
-        println!("Error loading configuration file: {}", e);
+        eprintln!("Error loading configuration file {}: {}", args.config, e);
         std::process::exit(1);
     }
-    let settings = settings.unwrap();
+    let mut settings = settings.unwrap();
+    for warning in settings.warnings() {
+        eprintln!("Warning: {}", warning);
+    }
+    if args.no_discovery {
+        settings.mqtt.discovery.enabled = false;
+    }
+
+    // Validate the files referenced by the configuration too
+    if args.check_config {
+        if let Some(Err(error)) = settings.mqtt.tls.as_ref().map(tls::client_config) {
+            eprintln!("Error loading TLS configuration: {}", error);
+            std::process::exit(1);
+        }
+        println!("Configuration file {} is valid", args.config);
+        return Ok(());
+    }
 
-    // Enable debugging
-    if settings.debug {
-        println!("Enabled debug output");
-        std::env::set_var("RUST_LOG", "error,mpqtt=trace,masterpower_api=trace");
+    // Enable logging, debug mode logs everything unless a level is given
+    let log_level = args.log_level.clone().or_else(|| if settings.debug { Some("trace".to_string()) } else { None });
+    if let Some(level) = log_level {
+        std::env::set_var("RUST_LOG", format!("error,mpqtt={},masterpower_api={}", level, level));
         pretty_env_logger::init();
+        info!("Logging at {} level", level);
     }
 
+    if args.once {
+        return poll_once(&settings).await;
+    }
+
+    // Mark the bridge offline if the connection drops without a clean shutdown
+    let mut last_will = PublishOpts::new(settings.mqtt.availability_topic.clone(), Vec::from("offline"));
+    last_will.set_qos(QoS::AtLeastOnce);
+    last_will.set_retain(true);
+
     // Create MQTT Connection
     info!("Connecting to MQTT Broker at: {}:{}", settings.mqtt.host, settings.mqtt.port);
     let mut builder = mqtt_async_client::client::Client::builder();
-    let mut mqtt_client = builder
+    builder
         .set_host(settings.mqtt.host.clone())
         .set_port(settings.mqtt.port)
-        .set_username(Option::from(settings.mqtt.username.clone()))
-        .set_password(Option::from(settings.mqtt.password.as_bytes().to_vec()))
+        .set_username(settings.mqtt.username.clone())
+        .set_password(settings.mqtt.password.as_ref().map(|password| password.as_bytes().to_vec()))
         .set_client_id(Option::from(settings.mqtt.client_id.clone()))
-        .set_connect_retry_delay(Duration::from_secs(1))
-        .set_keep_alive(KeepAlive::from_secs(5))
-        .set_operation_timeout(Duration::from_secs(5))
+        .set_connect_retry_delay(Duration::from_secs(settings.mqtt.connect_retry_delay))
+        .set_keep_alive(KeepAlive::from_secs(settings.mqtt.keep_alive))
+        .set_operation_timeout(Duration::from_secs(settings.mqtt.operation_timeout))
+        .set_clean_session(settings.mqtt.clean_session)
         .set_automatic_connect(true)
-        .build()?;
+        .set_last_will(Some(last_will));
+
+    // Encrypt the connection, optionally authenticating with a client certificate
+    if let Some(tls) = &settings.mqtt.tls {
+        match tls::client_config(tls) {
+            Ok(config) => builder.set_tls_client_config(config),
+            Err(error) => {
+                eprintln!("Error loading TLS configuration: {}", error);
+                std::process::exit(1);
+            }
+        };
+    }
+    let mut mqtt_client = builder.build()?;
 
     mqtt_client.connect().await?;
     info!("Connected to MQTT Broker");
+    let mqtt_client = SharedClient::new(mqtt_client);
+    publish_availability(&mqtt_client, &settings.mqtt, "online").await?;
+
+    // Stop on `systemctl stop` or Ctrl+C
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let (stop_sender, stop_receiver) = watch::channel(false);
+
+    // Listen for setting changes, each device gets the messages of its own topic
+    let devices = settings.devices();
+    let mut routes = Vec::new();
+    let mut receivers = Vec::new();
+    for device in devices.iter() {
+        subscribe_control_topics(&mqtt_client, &device.mqtt).await?;
+        let (sender, receiver) = unbounded_channel();
+        routes.push((device.mqtt.topic.clone(), sender));
+        receivers.push(receiver);
+    }
 
-    // Run MQTT Discovery
-    run_mqtt_discovery(&mqtt_client, &settings.mqtt).await?;
-
-    // Open inverter tty device
-    let stream = raw_open(settings.inverter.path.clone());
-
-    // Handle inverter error
-    if let Err(error) = stream {
-        publish_error(&mqtt_client, &settings.mqtt, error.to_string()).await?;
-        error!("Could not open inverter communication {}", error);
+    // Poll every inverter in its own task, an inverter that fails doesn't stop the others
+    let mqtt_client = Arc::new(mqtt_client);
+    let tasks = devices.into_iter().zip(receivers).map(|(device, messages)| tokio::spawn(run_inverter(mqtt_client.clone(), device, messages, stop_receiver.clone())));
+    let inverters = join_all(tasks);
+    tokio::pin!(inverters);
+    let mut stopping = false;
+    let results = loop {
+        tokio::select! {
+            results = &mut inverters => break results,
+            _ = route_control_messages(&mqtt_client, &routes) => {}
+            _ = shutdown_signal(&mut sigterm, &mut sigint), if !stopping => {
+                stop_sender.broadcast(true)?;
+                stopping = true;
+            }
+        }
+    };
+
+    // The last will is not sent on a regular disconnect
+    shutdown(&mqtt_client, &settings.mqtt).await?;
+    let mut failed = false;
+    for result in results {
+        let error = match result {
+            Ok(Ok(())) => continue,
+            Ok(Err(error)) => error,
+            Err(error) => error.to_string(),
+        };
+        error!("{}", error);
+        failed = true;
+    }
+    if failed {
         std::process::exit(1);
     }
+    info!("Stopped");
 
-    // Clear previous errors
-    clear_error(&mqtt_client, &settings.mqtt).await?;
+    Ok(())
+}
 
-    // Create inverter instance
-    let mut inverter = Inverter::from_stream(stream.unwrap());
+/// Polls one inverter until a shutdown is requested. Runs in its own task, so errors are returned as strings
+async fn run_inverter(mqtt_client: Arc<SharedClient>, device: Device, messages: UnboundedReceiver<ControlMessage>, stop: watch::Receiver<bool>) -> Result<(), String> {
+    poll_inverter(&mqtt_client, &device, messages, stop).await.map_err(|error| format!("Inverter {} stopped: {}", device.mqtt.topic, error))
+}
 
-    // Start
-    let init_res = init(&mut inverter, &mqtt_client, &settings).await;
-    if let Err(error) = init_res {
-        publish_error(&mqtt_client, &settings.mqtt, error.to_string()).await?;
-        error!("{}", error);
-        std::process::exit(1);
+/// Opens, initializes and polls one inverter. Error values are never kept across an await so the future stays Send.
+async fn poll_inverter(mqtt_client: &SharedClient, device: &Device, mut messages: UnboundedReceiver<ControlMessage>, mut stop: watch::Receiver<bool>) -> Result<(), Box<dyn std::error::Error>> {
+    // Run MQTT Discovery
+    if device.mqtt.discovery.enabled {
+        run_mqtt_discovery(&mqtt_client, &device.mqtt).await?;
+        if device.inverter.parallel_units > 0 {
+            run_parallel_discovery(&mqtt_client, &device.mqtt, device.inverter.parallel_units).await?;
+        }
     }
 
+    // Open and initialize the inverter, one that isn't ready yet is retried like a lost connection
+    let started = start_inverter(&mqtt_client, &device).await;
+    let mut inverter = match started {
+        Ok(inverter) => {
+            clear_error(&mqtt_client, &device.mqtt).await?;
+            publish_inverter_availability(&mqtt_client, &device.mqtt, "online").await?;
+            inverter
+        }
+        Err(error) => {
+            publish_error(&mqtt_client, &device.mqtt, error.clone()).await?;
+            error!("{}", error);
+            let reconnected = tokio::select! {
+                inverter = reconnect(&mqtt_client, &device) => Some(inverter?),
+                _ = stopped(&mut stop) => None,
+            };
+            match reconnected {
+                Some(inverter) => inverter,
+                None => {
+                    clear_error(&mqtt_client, &device.mqtt).await?;
+                    return Ok(());
+                }
+            }
+        }
+    };
+
     // Update loop
+    let mut scheduler = Scheduler::new(&device.polling);
+    let mut change_filter = ChangeFilter::new(&device.publish);
+    let mut failures = 0;
     loop {
-        // Do update
-        let upd = update(&mut inverter, &mqtt_client, &settings).await;
-        if let Err(error) = upd {
-            publish_error(&mqtt_client, &settings.mqtt, error.to_string()).await?;
+        // Poll the commands that are due, a shutdown is only checked between updates so the inverter never sees a partial frame
+        if !scheduler.due().is_empty() {
+            let upd = update(&mut inverter, &mqtt_client, &device, &mut scheduler, &mut change_filter).await.map_err(|error| (error.to_string(), is_connection_lost(error.as_ref())));
+            if let Err((error, connection_lost)) = upd {
+                publish_error(&mqtt_client, &device.mqtt, error.clone()).await?;
+                error!("{}", error);
+
+                // Close the dead device and wait until it comes back
+                if connection_lost || !is_device_present(&device.inverter) {
+                    drop(inverter);
+                    inverter = tokio::select! {
+                        inverter = reconnect(&mqtt_client, &device) => inverter?,
+                        _ = stopped(&mut stop) => break,
+                    };
+                    failures = 0;
+                    continue;
+                }
+
+                // Mark the inverter offline after too many consecutive failures
+                failures += 1;
+                if failures == device.inverter.max_failures {
+                    warn!("Inverter unreachable after {} failed updates", failures);
+                    publish_inverter_availability(&mqtt_client, &device.mqtt, "offline").await?;
+                }
+            } else {
+                clear_error(&mqtt_client, &device.mqtt).await?;
+
+                if failures >= device.inverter.max_failures {
+                    info!("Inverter reachable again");
+                    publish_inverter_availability(&mqtt_client, &device.mqtt, "online").await?;
+                }
+                failures = 0;
+            }
+        }
+
+        // Apply pending setting changes
+        if let Err(error) = handle_control_messages(&mut inverter, &mqtt_client, &device, &mut messages).await.map_err(|error| error.to_string()) {
+            publish_error(&mqtt_client, &device.mqtt, error.clone()).await?;
             error!("{}", error);
-        } else {
-            clear_error(&mqtt_client, &settings.mqtt).await?;
         }
 
-        // Sleep 1 sec
-        sleep(Duration::from_secs(1));
+        // Wait until the next command is due, checking for setting changes at least every second.
+        // Commands that failed are still due and get retried after a full second.
+        let wait = scheduler.time_until_next();
+        let wait = if wait.as_millis() == 0 { Duration::from_secs(1) } else { wait.min(Duration::from_secs(1)) };
+        tokio::select! {
+            _ = delay_for(wait) => {}
+            _ = stopped(&mut stop) => break,
+        }
+    }
+
+    // Leave a clean state behind
+    clear_error(&mqtt_client, &device.mqtt).await?;
+    publish_inverter_availability(&mqtt_client, &device.mqtt, "offline").await?;
+    Ok(())
+}
+
+/// Opens the inverter and reads its initial values
+async fn start_inverter(mqtt_client: &SharedClient, device: &Device) -> Result<Inverter<InverterStream>, String> {
+    let stream = open_inverter(&device.inverter).await.map_err(|error| format!("Could not open inverter communication {}", error))?;
+    let mut inverter = Inverter::from_stream(stream);
+    init(&mut inverter, mqtt_client, device).await.map_err(|error| error.to_string())?;
+    Ok(inverter)
+}
+
+/// Resolves on the first SIGTERM or SIGINT
+async fn shutdown_signal(sigterm: &mut Signal, sigint: &mut Signal) {
+    tokio::select! {
+        _ = sigterm.recv() => info!("Received SIGTERM, shutting down"),
+        _ = sigint.recv() => info!("Received SIGINT, shutting down"),
     }
 }
 
-async fn init(inverter: &mut Inverter<File>, mqtt_client: &MQTTClient, settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+/// Resolves once a shutdown was requested
+async fn stopped(stop: &mut watch::Receiver<bool>) {
+    while let Some(requested) = stop.recv().await {
+        if requested {
+            return;
+        }
+    }
+}
+
+/// Reopens the inverter device with exponential backoff until the init commands succeed again
+async fn reconnect(mqtt_client: &SharedClient, device: &Device) -> Result<Inverter<InverterStream>, Box<dyn std::error::Error>> {
+    warn!("Lost connection to the inverter, reconnecting");
+    publish_inverter_availability(&mqtt_client, &device.mqtt, "offline").await?;
+
+    let max_delay = Duration::from_secs(device.inverter.reconnect_max_delay);
+    let mut delay = Duration::from_secs(1);
+    loop {
+        delay_for(delay).await;
+        delay = (delay * 2).min(max_delay);
+
+        let stream = match open_inverter(&device.inverter).await {
+            Ok(stream) => stream,
+            Err(error) => {
+                debug!("Could not reopen {}: {}", device.inverter.path, error);
+                continue;
+            }
+        };
+
+        let mut inverter = Inverter::from_stream(stream);
+        if let Err(error) = init(&mut inverter, mqtt_client, device).await.map_err(|error| error.to_string()) {
+            publish_error(&mqtt_client, &device.mqtt, error.clone()).await?;
+            error!("Reconnected to inverter but init failed: {}", error);
+            continue;
+        }
+
+        info!("Reconnected to inverter");
+        clear_error(&mqtt_client, &device.mqtt).await?;
+        publish_inverter_availability(&mqtt_client, &device.mqtt, "online").await?;
+        return Ok(inverter);
+    }
+}
+
+/// Whether an update error means the device is gone, like a USB reset or an unplugged cable
+fn is_connection_lost(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<std::io::Error>() {
+            return match error.kind() {
+                ErrorKind::BrokenPipe | ErrorKind::NotConnected | ErrorKind::UnexpectedEof | ErrorKind::NotFound | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => true,
+                _ => matches!(error.raw_os_error(), Some(ENODEV) | Some(ENXIO) | Some(EIO) | Some(EBADF)),
+            };
+        }
+        source = error.source();
+    }
+    false
+}
+
+async fn init(inverter: &mut Inverter<InverterStream>, mqtt_client: &SharedClient, device: &Device) -> Result<(), Box<dyn std::error::Error>> {
     // Get initial values
 
     // QID      - Serial number
     let serial_number = inverter.execute::<QID>(()).await?;
-    publish_update(&mqtt_client, &settings.mqtt, "qid", serde_json::to_string(&serial_number)?).await?;
+    publish_update(&mqtt_client, &device.mqtt, "qid", serde_json::to_string(&serial_number)?).await?;
 
     // QPI      - Protocol ID
     let protocol_id = inverter.execute::<QPI>(()).await?;
-    publish_update(&mqtt_client, &settings.mqtt, "qpi", serde_json::to_string(&protocol_id)?).await?;
+    publish_update(&mqtt_client, &device.mqtt, "qpi", serde_json::to_string(&protocol_id)?).await?;
 
     // QVFW     - Software version 1
     let software_version_1 = inverter.execute::<QVFW>(()).await?;
-    publish_update(&mqtt_client, &settings.mqtt, "qvfw", serde_json::to_string(&software_version_1)?).await?;
+    publish_update(&mqtt_client, &device.mqtt, "qvfw", serde_json::to_string(&software_version_1)?).await?;
 
     // QVFW2     - Software version 2
     let software_version_2 = inverter.execute::<QVFW2>(()).await?;
-    publish_update(&mqtt_client, &settings.mqtt, "qvfw2", serde_json::to_string(&software_version_2)?).await?;
+    publish_update(&mqtt_client, &device.mqtt, "qvfw2", serde_json::to_string(&software_version_2)?).await?;
+
+    // QMCHGCR  - Selectable max charging currents, not every firmware supports it
+    let max_charging_currents = match inverter.execute::<QMCHGCR>(()).await {
+        Ok(currents) => {
+            publish_update(&mqtt_client, &device.mqtt, "qmchgcr", serde_json::to_string(&currents)?).await?;
+            Some(currents)
+        }
+        Err(error) => {
+            warn!("Could not read selectable max charging currents: {}", error);
+            None
+        }
+    };
+
+    // QMUCHGCR - Selectable max utility charging currents
+    let max_ac_charging_currents = match inverter.execute::<QMUCHGCR>(()).await {
+        Ok(currents) => {
+            publish_update(&mqtt_client, &device.mqtt, "qmuchgcr", serde_json::to_string(&currents)?).await?;
+            Some(currents)
+        }
+        Err(error) => {
+            warn!("Could not read selectable max AC charging currents: {}", error);
+            None
+        }
+    };
+
+    // QFLAG    - Device flags, not every firmware supports it
+    match inverter.execute::<QFLAG>(()).await {
+        Ok(flags) => publish_update(&mqtt_client, &device.mqtt, "qflag", serde_json::to_string(&flags)?).await?,
+        Err(error) => warn!("Could not read device flags: {}", error),
+    }
+
+    // QPIRI    - Battery rating voltage is needed to register the settings
+    let qpiri = inverter.execute::<QPIRI>(()).await?;
+    publish_qpiri(&mqtt_client, &device.mqtt, &qpiri).await?;
+    if device.mqtt.discovery.enabled {
+        run_control_discovery(&mqtt_client, &device.mqtt, qpiri.battery_rating_voltage as f32, max_charging_currents.as_ref(), max_ac_charging_currents.as_ref()).await?;
+    }
 
     Ok(())
 }
 
-async fn update(inverter: &mut Inverter<File>, mqtt_client: &MQTTClient, settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
-    // Start update
-    debug!("Starting update");
-    let start = Instant::now();
+/// Reads every command of every inverter once and prints them as a single json document, used by `--once`.
+/// With more than one inverter the document has an entry per topic suffix.
+async fn poll_once(settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+    let mut inverters = serde_json::Map::new();
+    for device in settings.devices() {
+        let state = read_once(&device).await?;
+        inverters.insert(device.inverter.topic_suffix.clone().unwrap_or_default(), Value::Object(state));
+    }
 
-    // QMOD     -  Device Mode Inquiry
-    let qmod = inverter.execute::<QMOD>(()).await?;
-    publish_update(&mqtt_client, &settings.mqtt, "qmod", serde_json::to_string(&qmod)?).await?;
+    let output = if inverters.len() == 1 { inverters.into_iter().next().unwrap().1 } else { Value::Object(inverters) };
+    println!("{}", serde_json::to_string_pretty(&output)?);
+    Ok(())
+}
 
-    // QPIRI    - Device Rating Information Inquiry
-    let qpiri = inverter.execute::<QPIRI>(()).await?;
-    publish_update(&mqtt_client, &settings.mqtt, "qpiri", serde_json::to_string(&qpiri)?).await?;
+async fn read_once(device: &Device) -> Result<serde_json::Map<String, Value>, Box<dyn std::error::Error>> {
+    let stream = open_inverter(&device.inverter).await.map_err(|error| format!("Could not open inverter communication {}", error))?;
+    let mut inverter = Inverter::from_stream(stream);
+
+    let mut output = serde_json::Map::new();
+    output.insert("qid".to_string(), serde_json::to_value(inverter.execute::<QID>(()).await?)?);
+    output.insert("qpi".to_string(), serde_json::to_value(inverter.execute::<QPI>(()).await?)?);
+    output.insert("qvfw".to_string(), serde_json::to_value(inverter.execute::<QVFW>(()).await?)?);
+    output.insert("qvfw2".to_string(), serde_json::to_value(inverter.execute::<QVFW2>(()).await?)?);
+    output.insert("qmod".to_string(), serde_json::to_value(inverter.execute::<QMOD>(()).await?)?);
+    output.insert("qpiri".to_string(), serde_json::to_value(inverter.execute::<QPIRI>(()).await?)?);
+    output.insert("qpigs".to_string(), serde_json::to_value(inverter.execute::<QPIGS>(()).await?)?);
+    output.insert("qpiws".to_string(), serde_json::to_value(inverter.execute::<QPIWS>(()).await?)?);
+    if device.polling.qflag > 0 {
+        output.insert("qflag".to_string(), serde_json::to_value(inverter.execute::<QFLAG>(()).await?)?);
+    }
+    if device.inverter.parallel_units > 0 {
+        let units = read_parallel_units(&mut inverter, device.inverter.parallel_units).await?;
+        output.insert("parallel".to_string(), serde_json::to_value(ParallelTotals::new(units.iter().map(|(_, unit)| unit)))?);
+        for (index, unit) in units {
+            output.insert(format!("qpgs{}", index), serde_json::to_value(unit)?);
+        }
+    }
+    Ok(output)
+}
 
-    // QPIGS    - Device general status parameters inquiry
-    let qpigs = inverter.execute::<QPIGS>(()).await?;
-    publish_update(&mqtt_client, &settings.mqtt, "qpigs", serde_json::to_string(&qpigs)?).await?;
+async fn update(inverter: &mut Inverter<InverterStream>, mqtt_client: &SharedClient, device: &Device, scheduler: &mut Scheduler, change_filter: &mut ChangeFilter) -> Result<(), Box<dyn std::error::Error>> {
+    // Start update
+    let due = scheduler.due();
+    debug!("Starting update of {:?}", due);
+    let start = Instant::now();
+
+    for command in due {
+        match command {
+            // QMOD     -  Device Mode Inquiry
+            PolledCommand::QMOD => {
+                let qmod = inverter.execute::<QMOD>(()).await?;
+                publish_polled(&mqtt_client, &device.mqtt, change_filter, "qmod", &qmod).await?;
+            }
+
+            // QPIRI    - Device Rating Information Inquiry
+            PolledCommand::QPIRI => {
+                let qpiri = inverter.execute::<QPIRI>(()).await?;
+                publish_qpiri(&mqtt_client, &device.mqtt, &qpiri).await?;
+            }
+
+            // QPIGS    - Device general status parameters inquiry
+            PolledCommand::QPIGS => {
+                let qpigs = inverter.execute::<QPIGS>(()).await?;
+                publish_polled(&mqtt_client, &device.mqtt, change_filter, "qpigs", &qpigs).await?;
+            }
+
+            // QPIWS    - Device Warning Status Inquiry
+            PolledCommand::QPIWS => {
+                let qpiws = inverter.execute::<QPIWS>(()).await?;
+                publish_polled(&mqtt_client, &device.mqtt, change_filter, "qpiws", &qpiws).await?;
+            }
+
+            // QFLAG    - Device flags
+            PolledCommand::QFLAG => {
+                let flags = inverter.execute::<QFLAG>(()).await?;
+                publish_polled(&mqtt_client, &device.mqtt, change_filter, "qflag", &flags).await?;
+            }
+
+            // QPGSn    - Parallel information of every unit, followed by the totals of the group
+            PolledCommand::QPGS => {
+                let units = read_parallel_units(inverter, device.inverter.parallel_units).await?;
+                for (index, unit) in &units {
+                    publish_polled(&mqtt_client, &device.mqtt, change_filter, &format!("qpgs{}", index), unit).await?;
+                }
+                publish_polled(&mqtt_client, &device.mqtt, change_filter, "parallel", &ParallelTotals::new(units.iter().map(|(_, unit)| unit))).await?;
+            }
+        }
 
-    // QPIWS    - Device Warning Status Inquiry
-    let qpiws = inverter.execute::<QPIWS>(()).await?;
-    publish_update(&mqtt_client, &settings.mqtt, "qpiws", serde_json::to_string(&qpiws)?).await?;
+        // Failed commands stay due and are retried on the next update
+        scheduler.done(command);
+    }
 
     // Report update completed
     debug!("Update finished without errors");
@@ -158,36 +518,110 @@ async fn update(inverter: &mut Inverter<File>, mqtt_client: &MQTTClient, setting
     Ok(())
 }
 
-async fn publish_update(mqtt_client: &MQTTClient, mqtt: &MqttSettings, command: &str, value: String) -> Result<(), Box<dyn std::error::Error>> {
-    let mut msg = PublishOpts::new(format!("{}/{}", mqtt.topic, command).to_string(), Vec::from(value));
-    msg.set_qos(QoS::AtLeastOnce);
-    msg.set_retain(false);
+/// Queries QPGS0 up to the last unit of the parallel group. A unit that does not answer is skipped so it
+/// doesn't fail the update of the connected inverter, only a lost connection is returned as an error.
+async fn read_parallel_units(inverter: &mut Inverter<InverterStream>, parallel_units: u8) -> Result<Vec<(u8, ParallelStatus)>, Box<dyn std::error::Error>> {
+    let mut units = Vec::new();
+    for index in 0..parallel_units {
+        match inverter.execute::<QPGS>(ParallelUnit(index)).await {
+            Ok(unit) => units.push((index, unit)),
+            Err(error) => {
+                let error: Box<dyn std::error::Error> = error.into();
+                if is_connection_lost(error.as_ref()) {
+                    return Err(error);
+                }
+                warn!("Skipping parallel unit {}: {}", index, error);
+            }
+        }
+    }
+    Ok(units)
+}
+
+async fn publish_update(mqtt_client: &SharedClient, mqtt: &MqttSettings, command: &str, value: String) -> Result<(), Box<dyn std::error::Error>> {
+    let topic = format!("{}/{}", mqtt.topic, command);
+
+    // Every field on its own topic as well, for clients that can't parse json
+    let mut fields = Vec::new();
+    if mqtt.flat_topics {
+        if let Ok(json) = serde_json::from_str::<Value>(&value) {
+            flatten_fields(&topic, &json, &mut fields);
+        }
+    }
+
+    let mut msg = PublishOpts::new(topic, Vec::from(value));
+    msg.set_qos(mqtt.topics.state.qos());
+    msg.set_retain(mqtt.topics.state.retain);
     mqtt_client.publish(&msg).await?;
+
+    for (topic, value) in fields {
+        let mut msg = PublishOpts::new(topic, Vec::from(value));
+        msg.set_qos(mqtt.topics.state.qos());
+        msg.set_retain(mqtt.topics.state.retain);
+        mqtt_client.publish(&msg).await?;
+    }
     Ok(())
 }
 
-async fn publish_error(mqtt_client: &MQTTClient, mqtt: &MqttSettings, error: String) -> Result<(), Box<dyn std::error::Error>> {
+/// Collects the topic and plain value of every field in a json object, nested objects become sub topics
+fn flatten_fields(topic: &str, value: &Value, fields: &mut Vec<(String, String)>) {
+    if let Value::Object(object) = value {
+        for (key, value) in object {
+            let topic = format!("{}/{}", topic, key);
+            match value {
+                Value::Object(_) => flatten_fields(&topic, value, fields),
+                Value::String(string) => fields.push((topic, string.clone())),
+                Value::Null => fields.push((topic, String::new())),
+                value => fields.push((topic, value.to_string())),
+            }
+        }
+    }
+}
+
+/// Publishes a polled command unless publish-on-change finds nothing new in it
+async fn publish_polled<T: Serialize>(mqtt_client: &SharedClient, mqtt: &MqttSettings, change_filter: &mut ChangeFilter, command: &str, value: &T) -> Result<(), Box<dyn std::error::Error>> {
+    if !change_filter.should_publish(command, &serde_json::to_value(value)?) {
+        debug!("Skipping unchanged {}", command);
+        return Ok(());
+    }
+    publish_update(mqtt_client, mqtt, command, serde_json::to_string(value)?).await
+}
+
+async fn publish_error(mqtt_client: &SharedClient, mqtt: &MqttSettings, error: String) -> Result<(), Box<dyn std::error::Error>> {
     let mut msg = PublishOpts::new(format!("{}/error", mqtt.topic).to_string(), Vec::from(error));
-    msg.set_qos(QoS::AtLeastOnce);
-    msg.set_retain(false);
+    msg.set_qos(mqtt.topics.error.qos());
+    msg.set_retain(mqtt.topics.error.retain);
     mqtt_client.publish(&msg).await?;
     Ok(())
 }
 
-async fn clear_error(mqtt_client: &MQTTClient, mqtt: &MqttSettings) -> Result<(), Box<dyn std::error::Error>> {
+async fn clear_error(mqtt_client: &SharedClient, mqtt: &MqttSettings) -> Result<(), Box<dyn std::error::Error>> {
     let mut msg = PublishOpts::new(format!("{}/error", mqtt.topic).to_string(), "".to_string().as_bytes().to_vec());
+    msg.set_qos(mqtt.topics.error.qos());
+    msg.set_retain(mqtt.topics.error.retain);
+    mqtt_client.publish(&msg).await?;
+    Ok(())
+}
+
+async fn publish_availability(mqtt_client: &SharedClient, mqtt: &MqttSettings, availability: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut msg = PublishOpts::new(mqtt.availability_topic.clone(), Vec::from(availability));
     msg.set_qos(QoS::AtLeastOnce);
-    msg.set_retain(false);
+    msg.set_retain(true);
     mqtt_client.publish(&msg).await?;
     Ok(())
 }
 
-fn raw_open<P: AsRef<Path>>(path: P) -> std::io::Result<File> {
-    let fd = unsafe { open(path.as_ref().as_os_str().as_bytes().as_ptr() as *const i8, O_RDWR) };
-    if fd < 0 {
-        return Err(std::io::Error::last_os_error());
-    }
+async fn publish_inverter_availability(mqtt_client: &SharedClient, mqtt: &MqttSettings, availability: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut msg = PublishOpts::new(format!("{}/inverter/availability", mqtt.topic).to_string(), Vec::from(availability));
+    msg.set_qos(QoS::AtLeastOnce);
+    msg.set_retain(true);
+    mqtt_client.publish(&msg).await?;
+    Ok(())
+}
 
-    let std_file = unsafe { std::fs::File::from_raw_fd(fd) };
-    Ok(File::from_std(std_file))
+/// Publishes the offline status before disconnecting, the last will is only sent on unclean disconnects
+async fn shutdown(mqtt_client: &SharedClient, mqtt: &MqttSettings) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Disconnecting from MQTT Broker");
+    publish_availability(mqtt_client, mqtt, "offline").await?;
+    mqtt_client.disconnect().await?;
+    Ok(())
 }
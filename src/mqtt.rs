@@ -0,0 +1,34 @@
+use mqtt_async_client::client::{Client, Publish, ReadResult, Subscribe};
+use mqtt_async_client::Result;
+use tokio::sync::Mutex;
+use tokio::time::{timeout, Duration};
+
+/// MQTT client shared by every inverter, the lock is only held for a single operation
+pub struct SharedClient {
+    client: Mutex<Client>,
+}
+
+impl SharedClient {
+    pub fn new(client: Client) -> Self {
+        SharedClient { client: Mutex::new(client) }
+    }
+
+    pub async fn publish(&self, msg: &Publish) -> Result<()> {
+        self.client.lock().await.publish(msg).await
+    }
+
+    pub async fn subscribe(&self, subscribe: Subscribe) -> Result<()> {
+        self.client.lock().await.subscribe(subscribe).await?;
+        Ok(())
+    }
+
+    /// Waits at most `wait` for a message on the subscribed topics, so publishing is never blocked for long
+    pub async fn read_subscriptions(&self, wait: Duration) -> Option<Result<ReadResult>> {
+        let mut client = self.client.lock().await;
+        timeout(wait, client.read_subscriptions()).await.ok()
+    }
+
+    pub async fn disconnect(&self) -> Result<()> {
+        self.client.lock().await.disconnect().await
+    }
+}
@@ -1,10 +1,18 @@
+use crate::commands::pbt::BatteryType;
+use crate::commands::pcp::ChargerSourcePriority;
+use crate::commands::pe::DeviceFlag;
+use crate::commands::pgr::InputVoltageRange;
+use crate::commands::pop::OutputSourcePriority;
+use crate::commands::qmchgcr::ChargingCurrents;
+use crate::control::battery_voltage_range;
+use crate::mqtt::SharedClient;
 use crate::settings::MqttSettings;
-use mqtt_async_client::client::{Client, Publish as PublishOpts, QoS};
+use mqtt_async_client::client::Publish as PublishOpts;
 use serde_derive::Serialize;
 
 use log::info;
 
-pub async fn run_mqtt_discovery(client: &Client, cfg: &MqttSettings) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn run_mqtt_discovery(client: &SharedClient, cfg: &MqttSettings) -> Result<(), Box<dyn std::error::Error>> {
     info!("Running MQTT Discovery");
 
     // Register error sensor
@@ -33,7 +41,7 @@ pub async fn run_mqtt_discovery(client: &Client, cfg: &MqttSettings) -> Result<(
     register_sensor(client, cfg, "qpiri", "ac_output_rating_voltage", "AC Output Rating Voltage", Some("V".to_string()), "power-plug").await?;
     register_sensor(client, cfg, "qpiri", "ac_out_rating_frequency", "AC Output Rating Frequency", Some("Hz".to_string()), "current-ac").await?;
     register_sensor(client, cfg, "qpiri", "ac_out_rating_current", "AC Output Rating Current", Some("A".to_string()), "current-dc").await?;
-    register_sensor(client, cfg, "qpiri", "ac_out_rating_apparent_power", "AC Output Rating Apparent Power", Some("W".to_string()), "power-plug").await?;
+    register_sensor(client, cfg, "qpiri", "ac_out_rating_apparent_power", "AC Output Rating Apparent Power", Some("VA".to_string()), "power-plug").await?;
     register_sensor(client, cfg, "qpiri", "ac_out_rating_active_power", "AC Output Rating Active Voltage", Some("W".to_string()), "power-plug").await?;
     register_sensor(client, cfg, "qpiri", "battery_rating_voltage", "Battery Rating Voltage", Some("V".to_string()), "current-dc").await?;
     register_sensor(client, cfg, "qpiri", "battery_recharge_voltage", "Battery Recharge Voltage", Some("V".to_string()), "current-dc").await?;
@@ -56,7 +64,7 @@ pub async fn run_mqtt_discovery(client: &Client, cfg: &MqttSettings) -> Result<(
     register_sensor(client, cfg, "qpigs", "grid_frequency", "Grid Frequency", Some("Hz".to_string()), "current-ac").await?;
     register_sensor(client, cfg, "qpigs", "ac_out_voltage", "Out Voltage", Some("V".to_string()), "power-plug").await?;
     register_sensor(client, cfg, "qpigs", "ac_out_frequency", "Out Frequency", Some("Hz".to_string()), "current-ac").await?;
-    register_sensor(client, cfg, "qpigs", "ac_out_apparent_power", "Out apparent power", Some("W".to_string()), "power-plug").await?;
+    register_sensor(client, cfg, "qpigs", "ac_out_apparent_power", "Out apparent power", Some("VA".to_string()), "power-plug").await?;
     register_sensor(client, cfg, "qpigs", "ac_out_active_power", "Out active power", Some("W".to_string()), "power-plug").await?;
     register_sensor(client, cfg, "qpigs", "out_load_percent", "Out load percent", Some("%".to_string()), "brightness-percent").await?;
     register_sensor(client, cfg, "qpigs", "bus_voltage", "Bus Voltage", Some("V".to_string()), "details").await?;
@@ -74,33 +82,112 @@ pub async fn run_mqtt_discovery(client: &Client, cfg: &MqttSettings) -> Result<(
     // Register QPIWS response
     register_sensor(client, cfg, "qpigs", "device_status.active_load", "Active load", None, "power").await?;
 
-    register_sensor(client, cfg, "qpiws", "inverter_fault", "Inverter fault", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "bus_over", "Bus over", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "bus_under", "Bus under", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "bus_soft_fail", "Bus soft fail", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "line_fail", "Line fail", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "opv_short", "OPV Short", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "inverter_voltage_too_low", "Inverter voltage too low", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "inverter_voltage_too_high", "Inverter voltage too high", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "over_temperature", "Over temperature", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "fan_locked", "Fan locked", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "battery_voltage_high", "Battery voltage high", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "battery_low_alarm", "Battery low alarm", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "battery_under_shutdown", "Battery under shutdown", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "over_load", "Over load", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "eeprom_fault", "EEPROM Fault", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "inverter_over_current", "Inverter over current", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "inverter_soft_fail", "Inverter soft fail", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "self_test_fail", "Self test fail", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "op_dc_voltage_over", "OP DC Voltage over", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "bat_open", "Bat open", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "current_sensor_fail", "Current sensor fail", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "battery_short", "Battery short", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "power_limit", "Power limit", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "pv_voltage_high", "PV Voltage high", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "mppt_overload_fault", "MPPT Overload fault", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "mppt_overload_warning", "MPPT Overload warning", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "battery_too_low_to_charge", "Battery too low to charge", None, "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "inverter_fault", "Inverter fault", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "bus_over", "Bus over", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "bus_under", "Bus under", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "bus_soft_fail", "Bus soft fail", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "line_fail", "Line fail", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "opv_short", "OPV Short", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "inverter_voltage_too_low", "Inverter voltage too low", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "inverter_voltage_too_high", "Inverter voltage too high", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "over_temperature", "Over temperature", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "fan_locked", "Fan locked", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "battery_voltage_high", "Battery voltage high", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "battery_low_alarm", "Battery low alarm", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "battery_under_shutdown", "Battery under shutdown", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "over_load", "Over load", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "eeprom_fault", "EEPROM Fault", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "inverter_over_current", "Inverter over current", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "inverter_soft_fail", "Inverter soft fail", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "self_test_fail", "Self test fail", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "op_dc_voltage_over", "OP DC Voltage over", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "bat_open", "Bat open", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "current_sensor_fail", "Current sensor fail", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "battery_short", "Battery short", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "power_limit", "Power limit", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "pv_voltage_high", "PV Voltage high", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "mppt_overload_fault", "MPPT Overload fault", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "mppt_overload_warning", "MPPT Overload warning", "alert").await?;
+    register_binary_sensor(client, cfg, "qpiws", "battery_too_low_to_charge", "Battery too low to charge", "alert").await?;
+
+    Ok(())
+}
+
+/// Registers the writable settings, ranges depend on the battery rating voltage and the currents the firmware accepts
+pub async fn run_control_discovery(client: &SharedClient, cfg: &MqttSettings, battery_rating_voltage: f32, max_charging_currents: Option<&ChargingCurrents>, max_ac_charging_currents: Option<&ChargingCurrents>) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Running MQTT Discovery for settings");
+
+    // Register enum settings
+    register_select(client, cfg, "output_source_priority", "Output Source Priority", &OutputSourcePriority::OPTIONS, "power-plug").await?;
+    register_select(client, cfg, "charger_source_priority", "Charger Source Priority", &ChargerSourcePriority::OPTIONS, "power-plug").await?;
+    register_select(client, cfg, "battery_type", "Battery Type", &BatteryType::OPTIONS, "battery").await?;
+    register_select(client, cfg, "input_voltage_range", "Input Voltage Range", &InputVoltageRange::OPTIONS, "power-plug").await?;
+
+    // Register charging currents, only when the firmware reports the selectable values
+    if let Some(currents) = max_charging_currents {
+        register_current_select(client, cfg, "max_charging_current", "Max Charging Current", currents).await?;
+    }
+    if let Some(currents) = max_ac_charging_currents {
+        register_current_select(client, cfg, "max_ac_charging_current", "Max AC Charging Current", currents).await?;
+    }
+
+    // Register battery voltages
+    register_voltage_number(client, cfg, battery_rating_voltage, "battery_recharge_voltage", "Battery Recharge Voltage").await?;
+    register_voltage_number(client, cfg, battery_rating_voltage, "battery_redischarge_voltage", "Battery Redischarge Voltage").await?;
+    register_voltage_number(client, cfg, battery_rating_voltage, "battery_under_voltage", "Battery Under Voltage").await?;
+    register_voltage_number(client, cfg, battery_rating_voltage, "battery_bulk_voltage", "Battery Bulk Voltage").await?;
+    register_voltage_number(client, cfg, battery_rating_voltage, "battery_float_voltage", "Battery Float Voltage").await?;
+
+    // Register device flags
+    register_switch(client, cfg, DeviceFlag::Buzzer, "Buzzer", "volume-high").await?;
+    register_switch(client, cfg, DeviceFlag::OverloadBypass, "Overload Bypass", "transit-detour").await?;
+    register_switch(client, cfg, DeviceFlag::PowerSaving, "Power Saving", "leaf").await?;
+    register_switch(client, cfg, DeviceFlag::LcdEscape, "LCD Escape To Default Page", "monitor").await?;
+    register_switch(client, cfg, DeviceFlag::OverloadRestart, "Overload Restart", "restart").await?;
+    register_switch(client, cfg, DeviceFlag::OverTemperatureRestart, "Over Temperature Restart", "thermometer-alert").await?;
+    register_switch(client, cfg, DeviceFlag::Backlight, "Backlight", "lightbulb-on").await?;
+    register_switch(client, cfg, DeviceFlag::PrimarySourceInterruptAlarm, "Primary Source Interrupt Alarm", "alarm-light").await?;
+    register_switch(client, cfg, DeviceFlag::FaultCodeRecord, "Fault Code Record", "record-rec").await?;
+
+    Ok(())
+}
+
+/// Registers every unit of a parallel group as its own device, the totals belong to the inverter the bridge is connected to
+pub async fn run_parallel_discovery(client: &SharedClient, cfg: &MqttSettings, parallel_units: u8) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Running MQTT Discovery for {} parallel units", parallel_units);
+
+    for index in 0..parallel_units {
+        let mut unit_cfg = cfg.clone();
+        unit_cfg.discovery.device_name = format!("{} unit {}", cfg.discovery.device_name, index);
+        unit_cfg.discovery.device_id = format!("{}_unit{}", cfg.discovery.device_id, index);
+        let command = format!("qpgs{}", index);
+
+        // Register QPGSn Sensors
+        register_sensor(client, &unit_cfg, &command, "serial_number", "Serial number", None, "slot-machine").await?;
+        register_sensor(client, &unit_cfg, &command, "mode", "Device mode", None, "information-outline").await?;
+        register_sensor(client, &unit_cfg, &command, "phase", "Output phase", None, "sine-wave").await?;
+        register_sensor(client, &unit_cfg, &command, "fault_code", "Fault code", None, "alert").await?;
+        register_sensor(client, &unit_cfg, &command, "ac_out_voltage", "Out Voltage", Some("V".to_string()), "power-plug").await?;
+        register_sensor(client, &unit_cfg, &command, "ac_out_apparent_power", "Out apparent power", Some("VA".to_string()), "power-plug").await?;
+        register_sensor(client, &unit_cfg, &command, "ac_out_active_power", "Out active power", Some("W".to_string()), "power-plug").await?;
+        register_sensor(client, &unit_cfg, &command, "out_load_percent", "Out load percent", Some("%".to_string()), "brightness-percent").await?;
+        register_sensor(client, &unit_cfg, &command, "battery_voltage", "Battery Voltage", Some("V".to_string()), "battery-outline").await?;
+        register_sensor(client, &unit_cfg, &command, "battery_charge_current", "Battery charge current", Some("A".to_string()), "current-dc").await?;
+        register_sensor(client, &unit_cfg, &command, "battery_discharge_current", "Battery discharge current", Some("A".to_string()), "battery-negative").await?;
+        register_sensor(client, &unit_cfg, &command, "battery_capacity", "Battery capacity", Some("%".to_string()), "battery-outline").await?;
+        register_sensor(client, &unit_cfg, &command, "pv_input_voltage", "PV Input Voltage", Some("V".to_string()), "solar-power").await?;
+        register_sensor(client, &unit_cfg, &command, "pv_input_current", "PV Input Current", Some("A".to_string()), "solar-power").await?;
+    }
+
+    // Register parallel totals
+    register_sensor(client, cfg, "parallel", "units", "Parallel units", None, "counter").await?;
+    register_sensor(client, cfg, "parallel", "ac_out_apparent_power", "Total out apparent power", Some("VA".to_string()), "power-plug").await?;
+    register_sensor(client, cfg, "parallel", "ac_out_active_power", "Total out active power", Some("W".to_string()), "power-plug").await?;
+    register_sensor(client, cfg, "parallel", "out_load_percent", "Average out load percent", Some("%".to_string()), "brightness-percent").await?;
+    register_sensor(client, cfg, "parallel", "battery_charge_current", "Total battery charge current", Some("A".to_string()), "current-dc").await?;
+    register_sensor(client, cfg, "parallel", "battery_discharge_current", "Total battery discharge current", Some("A".to_string()), "battery-negative").await?;
+    register_sensor(client, cfg, "parallel", "pv_input_current", "Total PV Input Current", Some("A".to_string()), "solar-power").await?;
+    register_sensor(client, cfg, "parallel", "pv_input_power", "Total PV Input Power", Some("W".to_string()), "solar-power").await?;
 
     Ok(())
 }
@@ -113,12 +200,84 @@ struct SensorDiscoveryParams {
     unit_of_measurement: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     value_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state_class: Option<String>,
     state_topic: String,
     icon: String,
+    availability: Vec<DiscoveryAvailability>,
+    availability_mode: String,
     device: SensorDiscoveryDevice,
     force_update: bool,
 }
 
+#[derive(Serialize, Debug)]
+struct SelectDiscoveryParams {
+    unique_id: String,
+    name: String,
+    state_topic: String,
+    value_template: String,
+    command_topic: String,
+    options: Vec<String>,
+    icon: String,
+    availability: Vec<DiscoveryAvailability>,
+    availability_mode: String,
+    device: SensorDiscoveryDevice,
+}
+
+#[derive(Serialize, Debug)]
+struct NumberDiscoveryParams {
+    unique_id: String,
+    name: String,
+    state_topic: String,
+    value_template: String,
+    command_topic: String,
+    min: f32,
+    max: f32,
+    step: f32,
+    unit_of_measurement: String,
+    icon: String,
+    availability: Vec<DiscoveryAvailability>,
+    availability_mode: String,
+    device: SensorDiscoveryDevice,
+}
+
+#[derive(Serialize, Debug)]
+struct SwitchDiscoveryParams {
+    unique_id: String,
+    name: String,
+    state_topic: String,
+    value_template: String,
+    command_topic: String,
+    payload_on: String,
+    payload_off: String,
+    icon: String,
+    availability: Vec<DiscoveryAvailability>,
+    availability_mode: String,
+    device: SensorDiscoveryDevice,
+}
+
+#[derive(Serialize, Debug)]
+struct BinarySensorDiscoveryParams {
+    unique_id: String,
+    name: String,
+    value_template: String,
+    state_topic: String,
+    payload_on: String,
+    payload_off: String,
+    device_class: String,
+    icon: String,
+    availability: Vec<DiscoveryAvailability>,
+    availability_mode: String,
+    device: SensorDiscoveryDevice,
+}
+
+#[derive(Serialize, Debug)]
+struct DiscoveryAvailability {
+    topic: String,
+}
+
 #[derive(Serialize, Debug)]
 struct SensorDiscoveryDevice {
     name: String,
@@ -138,45 +297,181 @@ fn get_device_hassio(cfg: &MqttSettings) -> SensorDiscoveryDevice {
     }
 }
 
-async fn register_error_sensor(client: &Client, cfg: &MqttSettings) -> Result<(), Box<dyn std::error::Error>> {
+/// Entities are only available while both the bridge and the inverter are online
+/// Entities that only depend on the bridge, like the last error, stay available while the inverter is offline
+fn get_bridge_availability(cfg: &MqttSettings) -> Vec<DiscoveryAvailability> {
+    vec![DiscoveryAvailability { topic: cfg.availability_topic.clone() }]
+}
+
+fn get_availability(cfg: &MqttSettings) -> Vec<DiscoveryAvailability> {
+    let mut availability = get_bridge_availability(cfg);
+    availability.push(DiscoveryAvailability {
+        topic: format!("{}/inverter/availability", cfg.topic).to_string(),
+    });
+    availability
+}
+
+async fn register_error_sensor(client: &SharedClient, cfg: &MqttSettings) -> Result<(), Box<dyn std::error::Error>> {
     info!("Registering error sensor");
     let params = SensorDiscoveryParams {
         unique_id: format!("{}_last_error", cfg.discovery.node_name).parse().unwrap(),
         name: format!("{} - Last error", cfg.discovery.device_name).to_string(),
         unit_of_measurement: None,
         value_template: None,
+        device_class: None,
+        state_class: None,
         state_topic: format!("{}/{}", cfg.topic, "error").to_string(),
         icon: "mdi:hammer-wrench".parse().unwrap(),
+        // Errors matter most while the inverter is unreachable, only follow the bridge availability
+        availability: get_bridge_availability(&cfg),
+        availability_mode: "all".to_string(),
         device: get_device_hassio(&cfg),
         force_update: false,
     };
-    let params_string = serde_json::to_string(&params)?;
-    let mut msg = PublishOpts::new(format!("{}/sensor/{}/{}/config", cfg.discovery.prefix, cfg.discovery.node_name, "error").to_string(), params_string.as_bytes().to_vec());
-    msg.set_qos(QoS::AtLeastOnce);
-    msg.set_retain(true);
-    client.publish(&msg).await?;
-    Ok(())
+    publish_config(client, cfg, "sensor", "error", serde_json::to_string(&params)?).await
 }
 
-async fn register_sensor(client: &Client, cfg: &MqttSettings, command: &str, id: &str, name: &str, unit: Option<String>, icon: &str) -> Result<(), Box<dyn std::error::Error>> {
+async fn register_sensor(client: &SharedClient, cfg: &MqttSettings, command: &str, id: &str, name: &str, unit: Option<String>, icon: &str) -> Result<(), Box<dyn std::error::Error>> {
     let unique_id = format!("{}_{}_{}", cfg.discovery.node_name, command, id).to_string().replace(".", "_");
     let topic = format!("{}/{}", cfg.topic, command).to_string();
 
+    // Numeric sensors get a device class from their unit so HA keeps long-term statistics
+    let device_class = match (id, unit.as_deref()) {
+        ("battery_capacity", _) => Some("battery"),
+        (_, Some("V")) => Some("voltage"),
+        (_, Some("A")) => Some("current"),
+        (_, Some("W")) => Some("power"),
+        (_, Some("VA")) => Some("apparent_power"),
+        (_, Some("Hz")) => Some("frequency"),
+        (_, Some("°C")) => Some("temperature"),
+        _ => None,
+    };
+    let state_class = unit.as_ref().map(|_| "measurement".to_string());
+
     info!("Registering sensor {}", unique_id);
     let params = SensorDiscoveryParams {
         unique_id,
         name: format!("{} - {}", cfg.discovery.device_name, name).to_string(),
         unit_of_measurement: unit,
         value_template: Some(format!("{{{{ value_json.{} }}}}", id).to_string()),
+        device_class: device_class.map(|class| class.to_string()),
+        state_class,
         state_topic: topic,
         icon: format!("mdi:{}", icon).to_string(),
+        availability: get_availability(&cfg),
+        availability_mode: "all".to_string(),
         device: get_device_hassio(&cfg),
         force_update: false,
     };
-    let params_string = serde_json::to_string(&params)?;
-    let mut msg = PublishOpts::new(format!("{}/sensor/{}/{}_{}/config", cfg.discovery.prefix, cfg.discovery.node_name, command, id.replace(".", "_")).to_string(), params_string.as_bytes().to_vec());
-    msg.set_qos(QoS::AtLeastOnce);
-    msg.set_retain(true);
+    publish_config(client, cfg, "sensor", &format!("{}_{}", command, id.replace(".", "_")), serde_json::to_string(&params)?).await
+}
+
+async fn register_binary_sensor(client: &SharedClient, cfg: &MqttSettings, command: &str, id: &str, name: &str, icon: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let unique_id = format!("{}_{}_{}", cfg.discovery.node_name, command, id).to_string();
+    let object_id = format!("{}_{}", command, id).to_string();
+
+    info!("Registering binary sensor {}", unique_id);
+    let params = BinarySensorDiscoveryParams {
+        unique_id,
+        name: format!("{} - {}", cfg.discovery.device_name, name).to_string(),
+        value_template: format!("{{{{ value_json.{} | tojson }}}}", id).to_string(),
+        state_topic: format!("{}/{}", cfg.topic, command).to_string(),
+        payload_on: "true".to_string(),
+        payload_off: "false".to_string(),
+        device_class: "problem".to_string(),
+        icon: format!("mdi:{}", icon).to_string(),
+        availability: get_availability(&cfg),
+        availability_mode: "all".to_string(),
+        device: get_device_hassio(&cfg),
+    };
+    publish_config(client, cfg, "binary_sensor", &object_id, serde_json::to_string(&params)?).await?;
+
+    // Remove the plain sensor previously registered for this flag
+    publish_config(client, cfg, "sensor", &object_id, "".to_string()).await
+}
+
+async fn register_select(client: &SharedClient, cfg: &MqttSettings, setting: &str, name: &str, options: &[&str], icon: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let unique_id = format!("{}_{}", cfg.discovery.node_name, setting).to_string();
+
+    info!("Registering select {}", unique_id);
+    let params = SelectDiscoveryParams {
+        unique_id,
+        name: format!("{} - {}", cfg.discovery.device_name, name).to_string(),
+        state_topic: format!("{}/settings", cfg.topic).to_string(),
+        value_template: format!("{{{{ value_json.{} }}}}", setting).to_string(),
+        command_topic: format!("{}/set/{}", cfg.topic, setting).to_string(),
+        options: options.iter().map(|option| option.to_string()).collect(),
+        icon: format!("mdi:{}", icon).to_string(),
+        availability: get_availability(&cfg),
+        availability_mode: "all".to_string(),
+        device: get_device_hassio(&cfg),
+    };
+    publish_config(client, cfg, "select", setting, serde_json::to_string(&params)?).await
+}
+
+/// The selectable currents are not evenly spaced, so they are offered as a list instead of a number range
+async fn register_current_select(client: &SharedClient, cfg: &MqttSettings, setting: &str, name: &str, currents: &ChargingCurrents) -> Result<(), Box<dyn std::error::Error>> {
+    let options: Vec<String> = currents.currents.iter().map(|current| current.to_string()).collect();
+    let options: Vec<&str> = options.iter().map(|option| option.as_str()).collect();
+    register_select(client, cfg, setting, name, &options, "current-ac").await
+}
+
+async fn register_voltage_number(client: &SharedClient, cfg: &MqttSettings, battery_rating_voltage: f32, setting: &str, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut min, max) = battery_voltage_range(setting, battery_rating_voltage)?;
+
+    // Re-discharge voltage also accepts 0 for "battery fully charged"
+    if setting == "battery_redischarge_voltage" {
+        min = 0.0;
+    }
+    register_number(client, cfg, setting, name, (min, max, 0.1), "V", "battery-outline").await
+}
+
+async fn register_number(client: &SharedClient, cfg: &MqttSettings, setting: &str, name: &str, (min, max, step): (f32, f32, f32), unit: &str, icon: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let unique_id = format!("{}_{}", cfg.discovery.node_name, setting).to_string();
+
+    info!("Registering number {}", unique_id);
+    let params = NumberDiscoveryParams {
+        unique_id,
+        name: format!("{} - {}", cfg.discovery.device_name, name).to_string(),
+        state_topic: format!("{}/qpiri", cfg.topic).to_string(),
+        value_template: format!("{{{{ value_json.{} }}}}", setting).to_string(),
+        command_topic: format!("{}/set/{}", cfg.topic, setting).to_string(),
+        min,
+        max,
+        step,
+        unit_of_measurement: unit.to_string(),
+        icon: format!("mdi:{}", icon).to_string(),
+        availability: get_availability(&cfg),
+        availability_mode: "all".to_string(),
+        device: get_device_hassio(&cfg),
+    };
+    publish_config(client, cfg, "number", setting, serde_json::to_string(&params)?).await
+}
+
+async fn register_switch(client: &SharedClient, cfg: &MqttSettings, flag: DeviceFlag, name: &str, icon: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let unique_id = format!("{}_{}", cfg.discovery.node_name, flag.name()).to_string();
+
+    info!("Registering switch {}", unique_id);
+    let params = SwitchDiscoveryParams {
+        unique_id,
+        name: format!("{} - {}", cfg.discovery.device_name, name).to_string(),
+        state_topic: format!("{}/qflag", cfg.topic).to_string(),
+        value_template: format!("{{{{ 'ON' if value_json.{} else 'OFF' }}}}", flag.name()).to_string(),
+        command_topic: format!("{}/set/{}", cfg.topic, flag.name()).to_string(),
+        payload_on: "ON".to_string(),
+        payload_off: "OFF".to_string(),
+        icon: format!("mdi:{}", icon).to_string(),
+        availability: get_availability(&cfg),
+        availability_mode: "all".to_string(),
+        device: get_device_hassio(&cfg),
+    };
+    publish_config(client, cfg, "switch", flag.name(), serde_json::to_string(&params)?).await
+}
+
+async fn publish_config(client: &SharedClient, cfg: &MqttSettings, component: &str, object_id: &str, params_string: String) -> Result<(), Box<dyn std::error::Error>> {
+    let mut msg = PublishOpts::new(format!("{}/{}/{}/{}/config", cfg.discovery.prefix, component, cfg.discovery.node_name, object_id).to_string(), params_string.as_bytes().to_vec());
+    msg.set_qos(cfg.topics.discovery.qos());
+    msg.set_retain(cfg.topics.discovery.retain);
     client.publish(&msg).await?;
     Ok(())
 }
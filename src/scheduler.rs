@@ -0,0 +1,113 @@
+use crate::settings::PollingSettings;
+
+use std::time::{Duration, Instant};
+
+/// Commands polled by the update loop, QID, QPI and the firmware versions are only read on init
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PolledCommand {
+    QMOD,
+    QPIRI,
+    QPIGS,
+    QPIWS,
+    QFLAG,
+    QPGS,
+}
+
+struct Task {
+    command: PolledCommand,
+    interval: Duration,
+    next: Instant,
+}
+
+/// Keeps track of when each command has to be polled again
+pub struct Scheduler {
+    tasks: Vec<Task>,
+}
+
+impl Scheduler {
+    /// Every command is due right away, an interval of 0 disables polling a command
+    pub fn new(polling: &PollingSettings) -> Self {
+        let now = Instant::now();
+        let tasks = [
+            (PolledCommand::QMOD, polling.qmod),
+            (PolledCommand::QPIRI, polling.qpiri),
+            (PolledCommand::QPIGS, polling.qpigs),
+            (PolledCommand::QPIWS, polling.qpiws),
+            (PolledCommand::QFLAG, polling.qflag),
+            (PolledCommand::QPGS, polling.qpgs),
+        ]
+        .iter()
+        .filter(|(_, interval)| *interval > 0)
+        .map(|(command, interval)| Task {
+            command: *command,
+            interval: Duration::from_secs(*interval),
+            next: now,
+        })
+        .collect();
+
+        Scheduler { tasks }
+    }
+
+    /// Commands whose interval has elapsed, they stay due until marked as done
+    pub fn due(&self) -> Vec<PolledCommand> {
+        let now = Instant::now();
+        self.tasks.iter().filter(|task| task.next <= now).map(|task| task.command).collect()
+    }
+
+    /// Schedules the next poll of a command after it was published
+    pub fn done(&mut self, command: PolledCommand) {
+        if let Some(task) = self.tasks.iter_mut().find(|task| task.command == command) {
+            task.next = Instant::now() + task.interval;
+        }
+    }
+
+    /// Time left until the next command is due
+    pub fn time_until_next(&self) -> Duration {
+        let now = Instant::now();
+        self.tasks.iter().map(|task| task.next.saturating_duration_since(now)).min().unwrap_or_else(|| Duration::from_secs(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn polling(qmod: u64, qpigs: u64) -> PollingSettings {
+        PollingSettings { qmod, qpiri: 0, qpigs, qpiws: 0, qflag: 0, qpgs: 0 }
+    }
+
+    #[test]
+    fn polls_every_enabled_command_right_away() {
+        let scheduler = Scheduler::new(&polling(5, 2));
+        assert_eq!(scheduler.due(), vec![PolledCommand::QMOD, PolledCommand::QPIGS]);
+        assert_eq!(scheduler.time_until_next(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn done_commands_wait_for_their_interval() {
+        let mut scheduler = Scheduler::new(&polling(5, 2));
+        scheduler.done(PolledCommand::QMOD);
+        assert_eq!(scheduler.due(), vec![PolledCommand::QPIGS]);
+
+        scheduler.done(PolledCommand::QPIGS);
+        assert!(scheduler.due().is_empty());
+        let wait = scheduler.time_until_next();
+        assert!(wait > Duration::from_secs(1) && wait <= Duration::from_secs(2), "{:?}", wait);
+    }
+
+    #[test]
+    fn failed_commands_stay_due() {
+        let mut scheduler = Scheduler::new(&polling(5, 2));
+        scheduler.done(PolledCommand::QMOD);
+        assert_eq!(scheduler.due(), vec![PolledCommand::QPIGS]);
+        assert_eq!(scheduler.due(), vec![PolledCommand::QPIGS]);
+    }
+
+    #[test]
+    fn ignores_disabled_commands() {
+        let mut scheduler = Scheduler::new(&polling(0, 0));
+        scheduler.done(PolledCommand::QFLAG);
+        assert!(scheduler.due().is_empty());
+        assert_eq!(scheduler.time_until_next(), Duration::from_secs(1));
+    }
+}
@@ -1,49 +1,536 @@
-use config::{Config, ConfigError, File};
+use config::{Config, ConfigError, Environment, File, Value};
+use mqtt_async_client::client::QoS;
+use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+use serde::de::{Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize as _;
 use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
 
 #[cfg(not(feature = "build-for-deb"))]
-const CONFIG_PATH: &'static str = "config.yaml";
+pub const CONFIG_PATH: &'static str = "config.yaml";
 
 #[cfg(feature = "build-for-deb")]
-const CONFIG_PATH: &'static str = "/etc/mpqtt/config.yaml";
+pub const CONFIG_PATH: &'static str = "/etc/mpqtt/config.yaml";
 
-#[derive(Debug, Deserialize)]
+/// Baud rates supported by the serial transport
+const BAUD_RATES: [u32; 8] = [1200, 2400, 4800, 9600, 19200, 38400, 57600, 115200];
+
+/// Keys of every section, anything else is most likely a typo. Sections not listed here, like
+/// `publish.deadband`, accept any key
+const KNOWN_KEYS: [(&str, &[&str]); 12] = [
+    ("", &["debug", "inverter", "polling", "publish", "mqtt"]),
+    ("inverter", &["path", "transport", "serial", "connect_timeout", "max_failures", "reconnect_max_delay", "topic_suffix", "device_id", "parallel_units"]),
+    ("inverter.serial", &["baud_rate", "data_bits", "parity", "stop_bits", "flow_control"]),
+    ("polling", &["qmod", "qpiri", "qpigs", "qpiws", "qflag", "qpgs"]),
+    ("publish", &["on_change", "refresh_interval", "deadband"]),
+    (
+        "mqtt",
+        &[
+            "host",
+            "port",
+            "username",
+            "password",
+            "password_file",
+            "client_id",
+            "keep_alive",
+            "operation_timeout",
+            "connect_retry_delay",
+            "clean_session",
+            "topics",
+            "topic",
+            "flat_topics",
+            "tls",
+            "discovery",
+        ],
+    ),
+    ("mqtt.topics", &["state", "error", "discovery"]),
+    ("mqtt.topics.state", &["qos", "retain"]),
+    ("mqtt.topics.error", &["qos", "retain"]),
+    ("mqtt.topics.discovery", &["qos", "retain"]),
+    ("mqtt.tls", &["ca_file", "client_cert", "client_key", "insecure_skip_verify"]),
+    ("mqtt.discovery", &["enabled", "prefix", "node_name", "device_name", "device_id"]),
+];
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Hidraw,
+    Serial,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FlowControl {
+    None,
+    Software,
+    Hardware,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SerialSettings {
+    pub baud_rate: u32,
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: u8,
+    pub flow_control: FlowControl,
+}
+
+impl Default for SerialSettings {
+    fn default() -> Self {
+        SerialSettings {
+            baud_rate: 2400,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+            flow_control: FlowControl::None,
+        }
+    }
+}
+
+/// Defaults are set here instead of in `Settings::new` because a list of inverters replaces the defaults of `inverter`
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
 pub struct InverterSettings {
     pub path: String,
+    pub transport: Transport,
+    pub serial: SerialSettings,
+    pub connect_timeout: u64,
+    pub max_failures: u32,
+    pub reconnect_max_delay: u64,
+    /// Appended to `mqtt.topic`, required when there is more than one inverter
+    pub topic_suffix: Option<String>,
+    /// Home Assistant device id, defaults to `mqtt.discovery.device_id` followed by the topic suffix
+    pub device_id: Option<String>,
+    /// Units in the parallel group, each one is queried with QPGS0 to QPGSn, 0 disables parallel mode
+    pub parallel_units: u8,
+}
+
+impl Default for InverterSettings {
+    fn default() -> Self {
+        InverterSettings {
+            path: "/dev/hidraw0".to_string(),
+            transport: Transport::Hidraw,
+            serial: SerialSettings::default(),
+            connect_timeout: 5,
+            max_failures: 3,
+            reconnect_max_delay: 60,
+            topic_suffix: None,
+            device_id: None,
+            parallel_units: 0,
+        }
+    }
+}
+
+/// Polling interval of each command in seconds, 0 disables it
+#[derive(Debug, Deserialize, Clone)]
+pub struct PollingSettings {
+    pub qmod: u64,
+    pub qpiri: u64,
+    pub qpigs: u64,
+    pub qpiws: u64,
+    pub qflag: u64,
+    pub qpgs: u64,
+}
+
+/// Publish-on-change, payloads are only published when a field changed by more than its deadband
+/// or when `refresh_interval` minutes passed since the last time
+#[derive(Debug, Deserialize, Clone)]
+pub struct PublishSettings {
+    pub on_change: bool,
+    pub refresh_interval: u64,
+    #[serde(default)]
+    pub deadband: HashMap<String, f64>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub struct MqttDiscovery {
+    pub enabled: bool,
     pub prefix: String,
     pub node_name: String,
     pub device_name: String,
     pub device_id: String,
 }
 
-#[derive(Debug, Deserialize)]
+/// TLS is enabled when the `mqtt.tls` section is present
+#[derive(Debug, Deserialize, Clone)]
+pub struct TlsSettings {
+    pub ca_file: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
+/// QoS level and retain flag used for a class of topics, mqtt-async-client only publishes with QoS 0 or 1
+#[derive(Debug, Deserialize, Clone)]
+pub struct TopicSettings {
+    pub qos: u8,
+    pub retain: bool,
+}
+
+impl TopicSettings {
+    pub fn qos(&self) -> QoS {
+        match self.qos {
+            0 => QoS::AtMostOnce,
+            _ => QoS::AtLeastOnce,
+        }
+    }
+}
+
+/// State covers the command payloads and setting results, availability is always retained with QoS 1
+#[derive(Debug, Deserialize, Clone)]
+pub struct TopicClasses {
+    pub state: TopicSettings,
+    pub error: TopicSettings,
+    pub discovery: TopicSettings,
+}
+
+#[derive(Debug, Deserialize, Clone)]
 pub struct MqttSettings {
     pub host: String,
     pub port: u16,
-    pub username: String,
-    pub password: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub password_file: Option<String>,
     pub client_id: String,
+    /// Bridge availability, shared by every inverter
+    #[serde(skip)]
+    pub availability_topic: String,
+    pub keep_alive: u16,
+    pub operation_timeout: u64,
+    pub connect_retry_delay: u64,
+    pub clean_session: bool,
+    pub topics: TopicClasses,
     pub topic: String,
+    pub flat_topics: bool,
+    pub tls: Option<TlsSettings>,
     pub discovery: MqttDiscovery,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub struct Settings {
     pub debug: bool,
+    #[serde(rename = "inverter", deserialize_with = "one_or_many", default = "default_inverters")]
+    pub inverters: Vec<InverterSettings>,
+    pub polling: PollingSettings,
+    pub publish: PublishSettings,
+    pub mqtt: MqttSettings,
+}
+
+/// Settings of a single inverter, with the MQTT topic and discovery names of its device
+#[derive(Debug, Clone)]
+pub struct Device {
     pub inverter: InverterSettings,
+    pub polling: PollingSettings,
+    pub publish: PublishSettings,
     pub mqtt: MqttSettings,
 }
 
+/// `inverter` is either a single inverter or a list of them
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<InverterSettings>, D::Error> {
+    struct InvertersVisitor;
+
+    impl<'de> Visitor<'de> for InvertersVisitor {
+        type Value = Vec<InverterSettings>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("an inverter or a list of inverters")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+            Ok(vec![InverterSettings::deserialize(MapAccessDeserializer::new(map))?])
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+            Vec::deserialize(SeqAccessDeserializer::new(seq))
+        }
+    }
+
+    deserializer.deserialize_any(InvertersVisitor)
+}
+
+fn default_inverters() -> Vec<InverterSettings> {
+    vec![InverterSettings::default()]
+}
+
 impl Settings {
-    pub fn new() -> Result<Self, ConfigError> {
+    pub fn new(path: &str) -> Result<Self, ConfigError> {
         let mut settings = Config::new();
 
-        settings.merge(File::with_name(CONFIG_PATH))?;
+        settings.set_default("debug", false)?;
+        settings.set_default("polling.qmod", 5)?;
+        settings.set_default("polling.qpiri", 300)?;
+        settings.set_default("polling.qpigs", 2)?;
+        settings.set_default("polling.qpiws", 10)?;
+        settings.set_default("polling.qflag", 0)?;
+        settings.set_default("polling.qpgs", 5)?;
+        settings.set_default("publish.on_change", false)?;
+        settings.set_default("publish.refresh_interval", 5)?;
+        settings.set_default("mqtt.host", "localhost")?;
+        settings.set_default("mqtt.port", 1883)?;
+        settings.set_default("mqtt.client_id", "mpqtt")?;
+        settings.set_default("mqtt.topic", "mpqtt/status")?;
+        settings.set_default("mqtt.keep_alive", 5)?;
+        settings.set_default("mqtt.operation_timeout", 5)?;
+        settings.set_default("mqtt.connect_retry_delay", 1)?;
+        settings.set_default("mqtt.clean_session", true)?;
+        settings.set_default("mqtt.flat_topics", false)?;
+        settings.set_default("mqtt.topics.state.qos", 1)?;
+        settings.set_default("mqtt.topics.state.retain", false)?;
+        settings.set_default("mqtt.topics.error.qos", 1)?;
+        settings.set_default("mqtt.topics.error.retain", false)?;
+        settings.set_default("mqtt.topics.discovery.qos", 1)?;
+        settings.set_default("mqtt.topics.discovery.retain", true)?;
+        settings.set_default("mqtt.discovery.enabled", true)?;
+        settings.set_default("mqtt.discovery.prefix", "homeassistant")?;
+        settings.set_default("mqtt.discovery.node_name", "mpqtt")?;
+        settings.set_default("mqtt.discovery.device_name", "MPQTT")?;
+        settings.set_default("mqtt.discovery.device_id", "mpqtt")?;
+        settings.merge(File::with_name(path))?;
+
+        // Every setting can be overridden from the environment, like MPQTT_MQTT__HOST for mqtt.host
+        settings.merge(Environment::with_prefix("MPQTT").separator("__"))?;
+
+        let mut problems = Vec::new();
+        check_keys(&mut problems, "", "", settings.clone().try_into()?);
+
+        let mut settings: Settings = settings.try_into()?;
+        settings.mqtt.availability_topic = format!("{}/availability", settings.mqtt.topic);
+        problems.extend(settings.validate());
+
+        // Secrets from a Docker or systemd credentials file take precedence over mqtt.password
+        if let Some(password_file) = settings.mqtt.password_file.clone() {
+            match std::fs::read_to_string(&password_file) {
+                Ok(password) => settings.mqtt.password = Some(password.trim_end_matches(|c| c == '\r' || c == '\n').to_string()),
+                Err(error) => problems.push(format!("mqtt.password_file: could not read {}: {}", password_file, error)),
+            }
+        }
+
+        if !problems.is_empty() {
+            return Err(ConfigError::Message(format!("{} problem(s) found\n  - {}", problems.len(), problems.join("\n  - "))));
+        }
+        Ok(settings)
+    }
+
+    /// One device per inverter, each one publishing under its own topic suffix and discovery ids
+    pub fn devices(&self) -> Vec<Device> {
+        self.inverters
+            .iter()
+            .map(|inverter| {
+                let mut mqtt = self.mqtt.clone();
+                if let Some(suffix) = &inverter.topic_suffix {
+                    mqtt.topic = format!("{}/{}", mqtt.topic, suffix);
+                    mqtt.discovery.node_name = format!("{}_{}", mqtt.discovery.node_name, suffix);
+                    mqtt.discovery.device_name = format!("{} {}", mqtt.discovery.device_name, suffix);
+                    mqtt.discovery.device_id = format!("{}_{}", mqtt.discovery.device_id, suffix);
+                }
+                if let Some(device_id) = &inverter.device_id {
+                    mqtt.discovery.device_id = device_id.clone();
+                }
+
+                // QPGS is only polled in parallel mode
+                let mut polling = self.polling.clone();
+                if inverter.parallel_units == 0 {
+                    polling.qpgs = 0;
+                }
+
+                Device {
+                    inverter: inverter.clone(),
+                    polling,
+                    publish: self.publish.clone(),
+                    mqtt,
+                }
+            })
+            .collect()
+    }
+
+    /// Checks the values serde can't, every problem is reported with the key path of the setting
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        // Inverters, keys of a list are reported as inverter[index]
+        for (index, inverter) in self.inverters.iter().enumerate() {
+            let key = if self.inverters.len() == 1 { "inverter".to_string() } else { format!("inverter[{}]", index) };
+            match inverter.path.strip_prefix("tcp://") {
+                Some(address) => match address.rfind(':').map(|colon| address.split_at(colon)) {
+                    Some((host, port)) if !host.is_empty() && port[1..].parse::<u16>().is_ok() => {}
+                    _ => problems.push(format!("{}.path: {} is not a valid tcp://host:port address", key, inverter.path)),
+                },
+                None if inverter.path.is_empty() => problems.push(format!("{}.path: must not be empty", key)),
+                None => {}
+            }
+            if !BAUD_RATES.contains(&inverter.serial.baud_rate) {
+                problems.push(format!("{}.serial.baud_rate: {} is not supported, use one of {:?}", key, inverter.serial.baud_rate, BAUD_RATES));
+            }
+            if !(5..=8).contains(&inverter.serial.data_bits) {
+                problems.push(format!("{}.serial.data_bits: must be between 5 and 8, got {}", key, inverter.serial.data_bits));
+            }
+            if inverter.serial.stop_bits != 1 && inverter.serial.stop_bits != 2 {
+                problems.push(format!("{}.serial.stop_bits: must be 1 or 2, got {}", key, inverter.serial.stop_bits));
+            }
+            if inverter.connect_timeout == 0 {
+                problems.push(format!("{}.connect_timeout: must be at least 1 second", key));
+            }
+            if inverter.max_failures == 0 {
+                problems.push(format!("{}.max_failures: must be at least 1", key));
+            }
+            if inverter.reconnect_max_delay == 0 {
+                problems.push(format!("{}.reconnect_max_delay: must be at least 1 second", key));
+            }
+            if inverter.parallel_units > 9 {
+                problems.push(format!("{}.parallel_units: at most 9 units can run in parallel, got {}", key, inverter.parallel_units));
+            }
+            if self.inverters.len() > 1 && inverter.topic_suffix.is_none() {
+                problems.push(format!("{}.topic_suffix: is required when there is more than one inverter", key));
+            }
+            if let Some(suffix) = &inverter.topic_suffix {
+                if suffix.is_empty() || !suffix.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                    problems.push(format!("{}.topic_suffix: {:?} may only contain letters, digits, _ and -", key, suffix));
+                }
+            }
+        }
+        let devices = self.devices();
+        for (index, device) in devices.iter().enumerate() {
+            if devices[..index].iter().any(|other| other.inverter.path == device.inverter.path) {
+                problems.push(format!("inverter[{}].path: {} is already used by another inverter", index, device.inverter.path));
+            }
+            if devices[..index].iter().any(|other| other.mqtt.topic == device.mqtt.topic) {
+                problems.push(format!("inverter[{}].topic_suffix: {} is already used by another inverter", index, device.mqtt.topic));
+            }
+            if devices[..index].iter().any(|other| other.mqtt.discovery.device_id == device.mqtt.discovery.device_id) {
+                problems.push(format!("inverter[{}].device_id: {} is already used by another inverter", index, device.mqtt.discovery.device_id));
+            }
+        }
+
+        // Publishing
+        if self.publish.on_change && self.publish.refresh_interval == 0 {
+            problems.push("publish.refresh_interval: must be at least 1 minute when publish.on_change is enabled".to_string());
+        }
+        for (field, deadband) in self.publish.deadband.iter() {
+            if *deadband < 0.0 || deadband.is_nan() {
+                problems.push(format!("publish.deadband.{}: must not be negative, got {}", field, deadband));
+            }
+        }
+
+        // MQTT
+        let mqtt = &self.mqtt;
+        if mqtt.host.is_empty() {
+            problems.push("mqtt.host: must not be empty".to_string());
+        }
+        if mqtt.port == 0 {
+            problems.push("mqtt.port: must not be 0".to_string());
+        }
+        if mqtt.username.is_none() && (mqtt.password.is_some() || mqtt.password_file.is_some()) {
+            problems.push("mqtt.username: is required when a password is set".to_string());
+        }
+        if mqtt.client_id.is_empty() {
+            problems.push("mqtt.client_id: must not be empty".to_string());
+        }
+        if mqtt.operation_timeout == 0 {
+            problems.push("mqtt.operation_timeout: must be at least 1 second".to_string());
+        }
+        check_topic(&mut problems, "mqtt.topic", &mqtt.topic);
+        for (class, topic) in [("state", &mqtt.topics.state), ("error", &mqtt.topics.error), ("discovery", &mqtt.topics.discovery)].iter() {
+            if topic.qos > 1 {
+                problems.push(format!("mqtt.topics.{}.qos: must be 0 or 1, got {}", class, topic.qos));
+            }
+        }
+
+        // TLS
+        if let Some(tls) = &mqtt.tls {
+            for (key, file) in [("ca_file", &tls.ca_file), ("client_cert", &tls.client_cert), ("client_key", &tls.client_key)].iter() {
+                if let Some(file) = file {
+                    if !Path::new(file).is_file() {
+                        problems.push(format!("mqtt.tls.{}: {} does not exist", key, file));
+                    }
+                }
+            }
+            if tls.client_cert.is_some() != tls.client_key.is_some() {
+                problems.push("mqtt.tls: client_cert and client_key must be set together".to_string());
+            }
+        }
+
+        // Discovery
+        let discovery = &mqtt.discovery;
+        if discovery.enabled {
+            check_topic(&mut problems, "mqtt.discovery.prefix", &discovery.prefix);
+            if discovery.node_name.is_empty() || !discovery.node_name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                problems.push(format!("mqtt.discovery.node_name: {:?} may only contain letters, digits, _ and -", discovery.node_name));
+            }
+            if discovery.device_id.is_empty() {
+                problems.push("mqtt.discovery.device_id: must not be empty".to_string());
+            }
+        }
+
+        problems
+    }
+
+    /// Settings that are valid but likely wrong. A missing device is only a warning, USB devices can
+    /// show up after mpqtt starts and the inverter is opened again until they do
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        for (index, inverter) in self.inverters.iter().enumerate() {
+            let key = if self.inverters.len() == 1 { "inverter".to_string() } else { format!("inverter[{}]", index) };
+            if !inverter.path.starts_with("tcp://") && !inverter.path.is_empty() && !Path::new(&inverter.path).exists() {
+                warnings.push(format!("{}.path: {} does not exist yet", key, inverter.path));
+            }
+        }
+        warnings
+    }
+}
+
+/// Reports the keys of a section that no setting uses, list items are checked against the keys of their section
+fn check_keys(problems: &mut Vec<String>, section: &str, path: &str, table: HashMap<String, Value>) {
+    let known = match KNOWN_KEYS.iter().find(|(name, _)| *name == section) {
+        Some((_, known)) => known,
+        None => return,
+    };
+
+    let mut keys: Vec<(String, Value)> = table.into_iter().collect();
+    keys.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (key, value) in keys {
+        let key_path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+        if !known.contains(&key.as_str()) {
+            problems.push(format!("{}: unknown setting, expected one of {}", key_path, known.join(", ")));
+            continue;
+        }
+
+        let child = if section.is_empty() { key } else { format!("{}.{}", section, key) };
+        match value.clone().into_array() {
+            Ok(items) => {
+                for (index, item) in items.into_iter().enumerate() {
+                    if let Ok(table) = item.into_table() {
+                        check_keys(problems, &child, &format!("{}[{}]", key_path, index), table);
+                    }
+                }
+            }
+            Err(_) => {
+                if let Ok(table) = value.into_table() {
+                    check_keys(problems, &child, &key_path, table);
+                }
+            }
+        }
+    }
+}
 
-        settings.try_into()
+/// Topics are used as a prefix, they can't be empty, hold wildcards or start or end with a slash
+fn check_topic(problems: &mut Vec<String>, key: &str, topic: &str) {
+    if topic.is_empty() {
+        problems.push(format!("{}: must not be empty", key));
+    } else if topic.contains('+') || topic.contains('#') {
+        problems.push(format!("{}: {} must not contain the + or # wildcards", key, topic));
+    } else if topic.starts_with('/') || topic.ends_with('/') {
+        problems.push(format!("{}: {} must not start or end with /", key, topic));
     }
 }
@@ -0,0 +1,65 @@
+use crate::settings::TlsSettings;
+
+use log::warn;
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use webpki::DNSNameRef;
+
+/// Builds the TLS configuration of the MQTT connection, the broker is checked against the webpki roots unless a CA file is given
+pub fn client_config(tls: &TlsSettings) -> Result<ClientConfig, Box<dyn std::error::Error>> {
+    let mut config = ClientConfig::new();
+
+    // Broker verification
+    match &tls.ca_file {
+        Some(ca_file) => {
+            let (added, _) = config.root_store.add_pem_file(&mut open(ca_file)?).map_err(|_| format!("Could not parse CA file {}", ca_file))?;
+            if added == 0 {
+                return Err(format!("No certificates found in CA file {}", ca_file).into());
+            }
+        }
+        None => config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+    }
+    if tls.insecure_skip_verify {
+        warn!("Broker certificate verification is disabled");
+        config.dangerous().set_certificate_verifier(Arc::new(NoCertificateVerification));
+    }
+
+    // Client authentication
+    match (&tls.client_cert, &tls.client_key) {
+        (Some(client_cert), Some(client_key)) => {
+            let cert_chain = certs(&mut open(client_cert)?).map_err(|_| format!("Could not parse client certificate {}", client_cert))?;
+            if cert_chain.is_empty() {
+                return Err(format!("No certificates found in {}", client_cert).into());
+            }
+            config.set_single_client_cert(cert_chain, private_key(client_key)?);
+        }
+        (None, None) => {}
+        _ => return Err("Both mqtt.tls.client_cert and mqtt.tls.client_key are needed for client authentication".into()),
+    }
+
+    Ok(config)
+}
+
+fn open(path: &str) -> Result<BufReader<File>, Box<dyn std::error::Error>> {
+    let file = File::open(path).map_err(|error| format!("Could not open {}: {}", path, error))?;
+    Ok(BufReader::new(file))
+}
+
+/// Reads the first PKCS#8 or RSA private key of a PEM file
+fn private_key(path: &str) -> Result<PrivateKey, Box<dyn std::error::Error>> {
+    let pkcs8 = pkcs8_private_keys(&mut open(path)?).map_err(|_| format!("Could not parse client key {}", path))?;
+    let rsa = rsa_private_keys(&mut open(path)?).map_err(|_| format!("Could not parse client key {}", path))?;
+    pkcs8.into_iter().chain(rsa).next().ok_or_else(|| format!("No private key found in {}", path).into())
+}
+
+/// Accepts any broker certificate, only meant for testing against self-signed brokers
+struct NoCertificateVerification;
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(&self, _roots: &RootCertStore, _presented_certs: &[Certificate], _dns_name: DNSNameRef<'_>, _ocsp_response: &[u8]) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
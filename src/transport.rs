@@ -0,0 +1,162 @@
+use crate::settings::{FlowControl, InverterSettings, Parity, SerialSettings, Transport};
+
+use libc::{cfmakeraw, cfsetispeed, cfsetospeed, open, tcflush, tcgetattr, tcsetattr, termios, O_NOCTTY, O_RDWR, TCIOFLUSH, TCSANOW};
+use libc::{B115200, B1200, B19200, B2400, B38400, B4800, B57600, B9600, CLOCAL, CREAD, CRTSCTS, CS5, CS6, CS7, CS8, CSIZE, CSTOPB, IXOFF, IXON, PARENB, PARODD, VMIN, VTIME};
+use std::ffi::CString;
+use std::io::{Error, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+
+const TCP_SCHEME: &str = "tcp://";
+
+/// Stream to the inverter, either a local device or a serial-to-network bridge
+pub enum InverterStream {
+    File(File),
+    Tcp(TcpStream),
+}
+
+impl AsyncRead for InverterStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            InverterStream::File(file) => Pin::new(file).poll_read(cx, buf),
+            InverterStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for InverterStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            InverterStream::File(file) => Pin::new(file).poll_write(cx, buf),
+            InverterStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            InverterStream::File(file) => Pin::new(file).poll_flush(cx),
+            InverterStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            InverterStream::File(file) => Pin::new(file).poll_shutdown(cx),
+            InverterStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Opens the inverter using the configured transport, paths like `tcp://host:port` connect to a network bridge
+pub async fn open_inverter(inverter: &InverterSettings) -> std::io::Result<InverterStream> {
+    if let Some(address) = inverter.path.strip_prefix(TCP_SCHEME) {
+        let connect = TcpStream::connect(address);
+        let stream = timeout(Duration::from_secs(inverter.connect_timeout), connect).await.map_err(|_| Error::new(ErrorKind::TimedOut, format!("Timed out connecting to {}", address)))??;
+        stream.set_nodelay(true)?;
+        return Ok(InverterStream::Tcp(stream));
+    }
+
+    match inverter.transport {
+        Transport::Hidraw => Ok(InverterStream::File(raw_open(&inverter.path, O_RDWR)?)),
+        Transport::Serial => {
+            let file = raw_open(&inverter.path, O_RDWR | O_NOCTTY)?;
+            configure_serial(file.as_raw_fd(), &inverter.serial)?;
+            Ok(InverterStream::File(file))
+        }
+    }
+}
+
+/// Whether the inverter device node still exists, network bridges are always considered present
+pub fn is_device_present(inverter: &InverterSettings) -> bool {
+    inverter.path.starts_with(TCP_SCHEME) || Path::new(&inverter.path).exists()
+}
+
+fn raw_open<P: AsRef<Path>>(path: P, flags: i32) -> std::io::Result<File> {
+    let path = CString::new(path.as_ref().as_os_str().as_bytes()).map_err(|_| Error::new(ErrorKind::InvalidInput, "Device path contains a NUL byte"))?;
+    let fd = unsafe { open(path.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let std_file = unsafe { std::fs::File::from_raw_fd(fd) };
+    Ok(File::from_std(std_file))
+}
+
+/// Puts the serial port in raw mode with the configured line settings
+fn configure_serial(fd: RawFd, serial: &SerialSettings) -> std::io::Result<()> {
+    let mut tty: termios = unsafe { std::mem::zeroed() };
+    if unsafe { tcgetattr(fd, &mut tty) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    unsafe { cfmakeraw(&mut tty) };
+
+    // Baud rate
+    let speed = match serial.baud_rate {
+        1200 => B1200,
+        2400 => B2400,
+        4800 => B4800,
+        9600 => B9600,
+        19200 => B19200,
+        38400 => B38400,
+        57600 => B57600,
+        115200 => B115200,
+        baud_rate => return Err(Error::new(ErrorKind::InvalidInput, format!("Unsupported baud rate {}", baud_rate))),
+    };
+    if unsafe { cfsetispeed(&mut tty, speed) } != 0 || unsafe { cfsetospeed(&mut tty, speed) } != 0 {
+        return Err(Error::last_os_error());
+    }
+
+    // Data bits
+    tty.c_cflag &= !CSIZE;
+    tty.c_cflag |= match serial.data_bits {
+        5 => CS5,
+        6 => CS6,
+        7 => CS7,
+        8 => CS8,
+        data_bits => return Err(Error::new(ErrorKind::InvalidInput, format!("Unsupported data bits {}", data_bits))),
+    };
+
+    // Parity
+    match serial.parity {
+        Parity::None => tty.c_cflag &= !(PARENB | PARODD),
+        Parity::Odd => tty.c_cflag |= PARENB | PARODD,
+        Parity::Even => {
+            tty.c_cflag |= PARENB;
+            tty.c_cflag &= !PARODD;
+        }
+    }
+
+    // Stop bits
+    match serial.stop_bits {
+        1 => tty.c_cflag &= !CSTOPB,
+        2 => tty.c_cflag |= CSTOPB,
+        stop_bits => return Err(Error::new(ErrorKind::InvalidInput, format!("Unsupported stop bits {}", stop_bits))),
+    }
+
+    // Flow control
+    tty.c_cflag &= !CRTSCTS;
+    tty.c_iflag &= !(IXON | IXOFF);
+    match serial.flow_control {
+        FlowControl::None => {}
+        FlowControl::Software => tty.c_iflag |= IXON | IXOFF,
+        FlowControl::Hardware => tty.c_cflag |= CRTSCTS,
+    }
+
+    // Enable the receiver, ignore modem lines and block until at least one byte arrives
+    tty.c_cflag |= CREAD | CLOCAL;
+    tty.c_cc[VMIN] = 1;
+    tty.c_cc[VTIME] = 0;
+
+    if unsafe { tcflush(fd, TCIOFLUSH) } != 0 || unsafe { tcsetattr(fd, TCSANOW, &tty) } != 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,370 @@
+//! Test harness shared by the end to end tests: a minimal in-process MQTT 3.1.1 broker, the
+//! inverter simulator and the mpqtt binary running against both.
+#![allow(dead_code)]
+
+use std::net::{TcpListener as StdTcpListener, TcpStream as StdTcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::time::delay_for;
+
+pub const TOPIC: &str = "mpqtt/status";
+pub const DISCOVERY_PREFIX: &str = "homeassistant";
+
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+impl Message {
+    pub fn payload_str(&self) -> String {
+        String::from_utf8_lossy(&self.payload).to_string()
+    }
+
+    pub fn payload_json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.payload).unwrap_or_else(|error| panic!("Payload on {} is not json: {}", self.topic, error))
+    }
+}
+
+#[derive(Default)]
+struct BrokerState {
+    messages: Vec<Message>,
+    subscribers: Vec<(String, UnboundedSender<Vec<u8>>)>,
+}
+
+/// Records every publish and forwards it to matching subscriptions, only QoS 0 and 1 are supported
+pub struct Broker {
+    port: u16,
+    state: Arc<Mutex<BrokerState>>,
+}
+
+impl Broker {
+    pub async fn start() -> Broker {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let state = Arc::new(Mutex::new(BrokerState::default()));
+
+        let accept_state = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, accept_state.clone()));
+            }
+        });
+
+        Broker { port, state }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Every message published so far on a topic
+    pub fn messages(&self, topic: &str) -> Vec<Message> {
+        self.state.lock().unwrap().messages.iter().filter(|message| message.topic == topic).cloned().collect()
+    }
+
+    /// Waits for the first message on a topic matching the predicate
+    pub async fn wait_for<F: Fn(&Message) -> bool>(&self, topic: &str, predicate: F, timeout: Duration) -> Message {
+        let start = Instant::now();
+        loop {
+            if let Some(message) = self.messages(topic).into_iter().find(|message| predicate(message)) {
+                return message;
+            }
+            if start.elapsed() > timeout {
+                panic!("Timed out waiting for a message on {}", topic);
+            }
+            delay_for(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Publishes a message to the subscribers, like an external client would
+    pub fn publish(&self, topic: &str, payload: &str) {
+        route(&self.state, topic, payload.as_bytes());
+    }
+}
+
+async fn serve(stream: TcpStream, state: Arc<Mutex<BrokerState>>) {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (sender, mut receiver) = unbounded_channel::<Vec<u8>>();
+
+    tokio::spawn(async move {
+        while let Some(packet) = receiver.recv().await {
+            if writer.write_all(&packet).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    while let Some((header, body)) = read_packet(&mut reader).await {
+        match header >> 4 {
+            // CONNECT
+            1 => send(&sender, 0x20, vec![0, 0]),
+            // PUBLISH
+            3 => {
+                let qos = (header >> 1) & 0b11;
+                let (topic, mut offset) = read_string(&body, 0);
+                if qos > 0 {
+                    send(&sender, 0x40, body[offset..offset + 2].to_vec());
+                    offset += 2;
+                }
+                let payload = body[offset..].to_vec();
+                state.lock().unwrap().messages.push(Message {
+                    topic: topic.clone(),
+                    payload: payload.clone(),
+                    retain: header & 1 == 1,
+                });
+                route(&state, &topic, &payload);
+            }
+            // SUBSCRIBE
+            8 => {
+                let mut granted = body[0..2].to_vec();
+                let mut offset = 2;
+                while offset < body.len() {
+                    let (filter, next) = read_string(&body, offset);
+                    granted.push(body[next].min(1));
+                    offset = next + 1;
+                    state.lock().unwrap().subscribers.push((filter, sender.clone()));
+                }
+                send(&sender, 0x90, granted);
+            }
+            // UNSUBSCRIBE
+            10 => send(&sender, 0xB0, body[0..2].to_vec()),
+            // PINGREQ
+            12 => send(&sender, 0xD0, vec![]),
+            // DISCONNECT
+            14 => break,
+            _ => {}
+        }
+    }
+}
+
+fn route(state: &Arc<Mutex<BrokerState>>, topic: &str, payload: &[u8]) {
+    let mut body = Vec::new();
+    body.extend_from_slice(&(topic.len() as u16).to_be_bytes());
+    body.extend_from_slice(topic.as_bytes());
+    body.extend_from_slice(payload);
+
+    let state = state.lock().unwrap();
+    for (filter, subscriber) in state.subscribers.iter() {
+        if topic_matches(filter, topic) {
+            send(subscriber, 0x30, body.clone());
+        }
+    }
+}
+
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut filter = filter.split('/');
+    let mut topic = topic.split('/');
+    loop {
+        match (filter.next(), topic.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(filter), Some(topic)) if filter == topic => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+fn send(sender: &UnboundedSender<Vec<u8>>, header: u8, body: Vec<u8>) {
+    let mut packet = vec![header];
+    let mut length = body.len();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend(body);
+    let _ = sender.send(packet);
+}
+
+async fn read_packet(reader: &mut ReadHalf<TcpStream>) -> Option<(u8, Vec<u8>)> {
+    let header = reader.read_u8().await.ok()?;
+    let mut length = 0usize;
+    let mut multiplier = 1;
+    loop {
+        let byte = reader.read_u8().await.ok()?;
+        length += (byte & 0x7F) as usize * multiplier;
+        multiplier *= 128;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await.ok()?;
+    Some((header, body))
+}
+
+fn read_string(body: &[u8], offset: usize) -> (String, usize) {
+    let length = u16::from_be_bytes([body[offset], body[offset + 1]]) as usize;
+    let start = offset + 2;
+    (String::from_utf8_lossy(&body[start..start + length]).to_string(), start + length)
+}
+
+/// Child process killed when the test ends
+pub struct Process(Child);
+
+impl Process {
+    /// Sends SIGTERM and waits for the process to exit, returns whether it exited successfully
+    pub async fn terminate(&mut self, timeout: Duration) -> bool {
+        unsafe { libc::kill(self.0.id() as libc::pid_t, libc::SIGTERM) };
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            if let Some(status) = self.0.try_wait().unwrap() {
+                return status.success();
+            }
+            // The broker runs on the test runtime, it must keep serving the final publishes
+            delay_for(Duration::from_millis(50)).await;
+        }
+        panic!("Process did not exit within {:?}", timeout);
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn free_port() -> u16 {
+    StdTcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// Starts the simulator with a scenario script, returns it along with its port
+pub fn start_simulator(name: &str, scenario: &str) -> (Process, u16) {
+    let port = free_port();
+    let dir = test_dir(name);
+    let scenario_path = dir.join("scenario.txt");
+    std::fs::write(&scenario_path, scenario).unwrap();
+
+    let child = Command::new(env!("CARGO_BIN_EXE_mpqtt-simulator")).arg(format!("127.0.0.1:{}", port)).arg(&scenario_path).stdout(Stdio::null()).stderr(Stdio::null()).spawn().unwrap();
+
+    // mpqtt backs off when the inverter can't be opened at startup, wait until the simulator listens
+    let start = Instant::now();
+    while StdTcpStream::connect(("127.0.0.1", port)).is_err() {
+        if start.elapsed() > Duration::from_secs(5) {
+            panic!("Simulator did not start listening on port {}", port);
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    (Process(child), port)
+}
+
+/// Generated config.yaml mpqtt runs with, pointing to the broker and to the simulators of its inverters
+pub struct MpqttConfig {
+    name: String,
+    broker_port: u16,
+    inverters: Vec<(Option<String>, u16)>,
+    parallel_units: u8,
+    extra_config: String,
+}
+
+impl MpqttConfig {
+    pub fn new(name: &str, broker: &Broker) -> MpqttConfig {
+        MpqttConfig {
+            name: name.to_string(),
+            broker_port: broker.port(),
+            inverters: Vec::new(),
+            parallel_units: 0,
+            extra_config: String::new(),
+        }
+    }
+
+    /// Single inverter behind the simulator listening on this port
+    pub fn inverter(mut self, simulator_port: u16) -> MpqttConfig {
+        self.inverters = vec![(None, simulator_port)];
+        self
+    }
+
+    /// Adds an inverter to the `inverter` list, published under its topic suffix
+    pub fn inverter_with_suffix(mut self, suffix: &str, simulator_port: u16) -> MpqttConfig {
+        self.inverters.push((Some(suffix.to_string()), simulator_port));
+        self
+    }
+
+    /// Units of the parallel group behind every inverter
+    pub fn parallel_units(mut self, parallel_units: u8) -> MpqttConfig {
+        self.parallel_units = parallel_units;
+        self
+    }
+
+    /// Extra yaml appended to config.yaml, right after the `mqtt` section
+    pub fn extra(mut self, extra_config: &str) -> MpqttConfig {
+        self.extra_config.push_str(extra_config);
+        self
+    }
+
+    /// Starts mpqtt, it is killed when the process is dropped
+    pub fn start(&self) -> Process {
+        let child = Command::new(env!("CARGO_BIN_EXE_mpqtt")).current_dir(self.write()).stdout(Stdio::null()).stderr(Stdio::null()).spawn().unwrap();
+        Process(child)
+    }
+
+    /// Runs mpqtt with command line arguments and environment variables until it exits
+    pub fn run(&self, args: &[&str], envs: &[(&str, &str)]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_mpqtt")).current_dir(self.write()).args(args).envs(envs.iter().cloned()).output().unwrap()
+    }
+
+    fn write(&self) -> PathBuf {
+        let mut inverter_config = String::new();
+        for (suffix, port) in self.inverters.iter() {
+            // Inverters with a topic suffix are items of a list
+            let indent = match suffix {
+                Some(suffix) => {
+                    inverter_config.push_str(&format!("  - topic_suffix: {}\n", suffix));
+                    "    "
+                }
+                None => "  ",
+            };
+            inverter_config.push_str(&format!("{}path: tcp://127.0.0.1:{}\n{}max_failures: 2\n", indent, port, indent));
+            if self.parallel_units > 0 {
+                inverter_config.push_str(&format!("{}parallel_units: {}\n", indent, self.parallel_units));
+            }
+        }
+
+        let dir = test_dir(&self.name);
+        let config = format!(
+            "debug: false
+
+inverter:
+{}
+mqtt:
+  host: 127.0.0.1
+  port: {}
+  username: mpqtt
+  password: mpqtt
+  client_id: mpqtt-{}
+  topic: {}
+  discovery:
+    prefix: {}
+    node_name: mpqtt
+    device_name: MPQTT
+    device_id: mpqtt
+{}
+",
+            inverter_config, self.broker_port, self.name, TOPIC, DISCOVERY_PREFIX, self.extra_config
+        );
+        std::fs::write(dir.join("config.yaml"), config).unwrap();
+        dir
+    }
+}
+
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mpqtt-test-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,451 @@
+mod common;
+
+use common::{start_simulator, Broker, Message, MpqttConfig, DISCOVERY_PREFIX, TOPIC};
+use serde_json::json;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+fn any(_: &Message) -> bool {
+    true
+}
+
+fn device() -> serde_json::Value {
+    json!({
+        "name": "MPQTT",
+        "identifiers": ["mpqtt"],
+        "model": "MPQTT",
+        "manufacturer": "MPQTT",
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    })
+}
+
+fn availability() -> serde_json::Value {
+    json!([
+        { "topic": format!("{}/availability", TOPIC) },
+        { "topic": format!("{}/inverter/availability", TOPIC) },
+    ])
+}
+
+#[tokio::test]
+async fn publishes_discovery_config() {
+    let broker = Broker::start().await;
+    let (_simulator, port) = start_simulator("discovery", "");
+    let _mpqtt = MpqttConfig::new("discovery", &broker).inverter(port).start();
+
+    let error = broker.wait_for(&format!("{}/sensor/mpqtt/error/config", DISCOVERY_PREFIX), any, TIMEOUT).await;
+    assert!(error.retain);
+    assert_eq!(
+        error.payload_json(),
+        json!({
+            "unique_id": "mpqtt_last_error",
+            "name": "MPQTT - Last error",
+            "state_topic": format!("{}/error", TOPIC),
+            "icon": "mdi:hammer-wrench",
+            "availability": [{ "topic": format!("{}/availability", TOPIC) }],
+            "availability_mode": "all",
+            "device": device(),
+            "force_update": false,
+        })
+    );
+
+    let battery_voltage = broker.wait_for(&format!("{}/sensor/mpqtt/qpigs_battery_voltage/config", DISCOVERY_PREFIX), any, TIMEOUT).await;
+    assert_eq!(
+        battery_voltage.payload_json(),
+        json!({
+            "unique_id": "mpqtt_qpigs_battery_voltage",
+            "name": "MPQTT - Battery Voltage",
+            "unit_of_measurement": "V",
+            "value_template": "{{ value_json.battery_voltage }}",
+            "device_class": "voltage",
+            "state_class": "measurement",
+            "state_topic": format!("{}/qpigs", TOPIC),
+            "icon": "mdi:battery-outline",
+            "availability": availability(),
+            "availability_mode": "all",
+            "device": device(),
+            "force_update": false,
+        })
+    );
+
+    let fan_locked = broker.wait_for(&format!("{}/binary_sensor/mpqtt/qpiws_fan_locked/config", DISCOVERY_PREFIX), any, TIMEOUT).await;
+    assert_eq!(
+        fan_locked.payload_json(),
+        json!({
+            "unique_id": "mpqtt_qpiws_fan_locked",
+            "name": "MPQTT - Fan locked",
+            "value_template": "{{ value_json.fan_locked | tojson }}",
+            "state_topic": format!("{}/qpiws", TOPIC),
+            "payload_on": "true",
+            "payload_off": "false",
+            "device_class": "problem",
+            "icon": "mdi:alert",
+            "availability": availability(),
+            "availability_mode": "all",
+            "device": device(),
+        })
+    );
+
+    let max_ac_charging_current = broker.wait_for(&format!("{}/select/mpqtt/max_ac_charging_current/config", DISCOVERY_PREFIX), any, TIMEOUT).await;
+    assert_eq!(
+        max_ac_charging_current.payload_json(),
+        json!({
+            "unique_id": "mpqtt_max_ac_charging_current",
+            "name": "MPQTT - Max AC Charging Current",
+            "state_topic": format!("{}/settings", TOPIC),
+            "value_template": "{{ value_json.max_ac_charging_current }}",
+            "command_topic": format!("{}/set/max_ac_charging_current", TOPIC),
+            "options": ["2", "10", "20", "30"],
+            "icon": "mdi:current-ac",
+            "availability": availability(),
+            "availability_mode": "all",
+            "device": device(),
+        })
+    );
+}
+
+#[tokio::test]
+async fn publishes_state() {
+    let broker = Broker::start().await;
+    let (_simulator, port) = start_simulator("state", "");
+    let _mpqtt = MpqttConfig::new("state", &broker).inverter(port).start();
+
+    // Payloads match the simulator defaults field by field
+    let qpigs = broker.wait_for(&format!("{}/qpigs", TOPIC), any, TIMEOUT).await.payload_json();
+    assert_eq!(
+        qpigs,
+        json!({
+            "grid_voltage": 230.0,
+            "grid_frequency": 50.0,
+            "ac_out_voltage": 230.0,
+            "ac_out_frequency": 50.0,
+            "ac_out_apparent_power": 460,
+            "ac_out_active_power": 420,
+            "out_load_percent": 9,
+            "bus_voltage": 460,
+            "battery_voltage": 52.8,
+            "battery_charge_current": 12,
+            "battery_capacity": 100,
+            "inverter_heat_sink_temp": 38,
+            "pv_input_current": 8,
+            "pv_input_voltage": 180.5,
+            "battery_scc_voltage": 52.8,
+            "battery_discharge_current": 0,
+            "device_status": {
+                "charge_status": "charging_floating",
+                "active_load": true,
+            },
+        })
+    );
+
+    let qpiri = broker.wait_for(&format!("{}/qpiri", TOPIC), any, TIMEOUT).await.payload_json();
+    assert_eq!(
+        qpiri,
+        json!({
+            "grid_rating_voltage": 230.0,
+            "grid_rating_current": 21.7,
+            "ac_output_rating_voltage": 230.0,
+            "ac_out_rating_frequency": 50.0,
+            "ac_out_rating_current": 21.7,
+            "ac_out_rating_apparent_power": 5000,
+            "ac_out_rating_active_power": 4000,
+            "battery_rating_voltage": 48.0,
+            "battery_recharge_voltage": 46.0,
+            "battery_under_voltage": 42.0,
+            "battery_bulk_voltage": 56.4,
+            "battery_float_voltage": 54.0,
+            "battery_type": "User",
+            "max_ac_charging_current": 30,
+            "max_charging_current": 60,
+            "input_voltage_range": "Appliance",
+            "output_source_priority": "SBUFirst",
+            "charge_source_priority": "SolarFirst",
+            "machine_type": "GridTie",
+            "topology": "TransformerLess",
+            "output_mode": "SingleMachine",
+            "battery_redischarge_voltage": 54.0,
+        })
+    );
+
+    let settings = broker.wait_for(&format!("{}/settings", TOPIC), any, TIMEOUT).await.payload_json();
+    assert_eq!(
+        settings,
+        json!({
+            "output_source_priority": "sbu",
+            "charger_source_priority": "solar",
+            "battery_type": "user",
+            "input_voltage_range": "appliance",
+            "max_charging_current": "60",
+            "max_ac_charging_current": "30",
+        })
+    );
+
+    let availability = broker.wait_for(&format!("{}/availability", TOPIC), any, TIMEOUT).await;
+    assert_eq!(availability.payload_str(), "online");
+    assert!(availability.retain);
+}
+
+#[tokio::test]
+async fn reports_failed_updates() {
+    let broker = Broker::start().await;
+    let (_simulator, port) = start_simulator("error", "0 nak QPIGS\n8 nak none\n");
+    let _mpqtt = MpqttConfig::new("error", &broker).inverter(port).start();
+
+    let error_topic = format!("{}/error", TOPIC);
+    let availability_topic = format!("{}/inverter/availability", TOPIC);
+
+    // Failed updates publish the error and mark the inverter offline
+    broker.wait_for(&error_topic, |message| !message.payload.is_empty(), TIMEOUT).await;
+    broker.wait_for(&availability_topic, |message| message.payload_str() == "offline", TIMEOUT).await;
+    assert!(broker.messages(&format!("{}/qpigs", TOPIC)).is_empty());
+
+    // Once the inverter answers again the error is cleared
+    broker.wait_for(&format!("{}/qpigs", TOPIC), any, TIMEOUT).await;
+    assert_eq!(broker.messages(&availability_topic).last().unwrap().payload_str(), "online");
+    assert!(broker.messages(&error_topic).last().unwrap().payload.is_empty());
+}
+
+#[tokio::test]
+async fn publishes_on_change() {
+    let broker = Broker::start().await;
+    let (_simulator, port) = start_simulator("on-change", "3 battery_voltage 52.90\n6 battery_voltage 53.60\n");
+    let _mpqtt = MpqttConfig::new("on-change", &broker).inverter(port).extra("publish:\n  on_change: true\n  refresh_interval: 5\n  deadband:\n    battery_voltage: 0.5\n").start();
+
+    // Changes within the deadband are not published, QPIGS is polled every 2 seconds
+    let qpigs_topic = format!("{}/qpigs", TOPIC);
+    broker.wait_for(&qpigs_topic, |message| (message.payload_json()["battery_voltage"].as_f64().unwrap() - 53.6).abs() < 0.01, TIMEOUT).await;
+    let voltages: Vec<f64> = broker.messages(&qpigs_topic).iter().map(|message| message.payload_json()["battery_voltage"].as_f64().unwrap()).collect();
+    assert_eq!(voltages.len(), 2, "published {:?}", voltages);
+    assert!((voltages[0] - 52.8).abs() < 0.01);
+}
+
+#[tokio::test]
+async fn publishes_flat_topics() {
+    let broker = Broker::start().await;
+    let (_simulator, port) = start_simulator("flat-topics", "");
+    let _mpqtt = MpqttConfig::new("flat-topics", &broker).inverter(port).extra("  flat_topics: true\n").start();
+
+    let battery_voltage = broker.wait_for(&format!("{}/qpigs/battery_voltage", TOPIC), any, TIMEOUT).await;
+    assert!((battery_voltage.payload_str().parse::<f64>().unwrap() - 52.8).abs() < 0.01);
+
+    // Nested fields and strings are published as plain values
+    let qpigs = broker.wait_for(&format!("{}/qpigs", TOPIC), any, TIMEOUT).await.payload_json();
+    let (key, value) = qpigs["device_status"].as_object().unwrap().iter().next().unwrap();
+    let nested = broker.wait_for(&format!("{}/qpigs/device_status/{}", TOPIC, key), any, TIMEOUT).await;
+    assert_eq!(nested.payload_str(), value.as_str().map(str::to_string).unwrap_or_else(|| value.to_string()));
+    let output_source_priority = broker.wait_for(&format!("{}/settings/output_source_priority", TOPIC), any, TIMEOUT).await;
+    assert_eq!(output_source_priority.payload_str(), "sbu");
+}
+
+#[tokio::test]
+async fn uses_configured_retain_flags() {
+    let broker = Broker::start().await;
+    let (_simulator, port) = start_simulator("retain", "");
+    let _mpqtt = MpqttConfig::new("retain", &broker).inverter(port).extra("  topics:\n    state:\n      qos: 0\n      retain: true\n    discovery:\n      qos: 1\n      retain: false\n").start();
+
+    assert!(broker.wait_for(&format!("{}/qpigs", TOPIC), any, TIMEOUT).await.retain);
+    assert!(!broker.wait_for(&format!("{}/sensor/mpqtt/error/config", DISCOVERY_PREFIX), any, TIMEOUT).await.retain);
+    assert!(!broker.wait_for(&format!("{}/error", TOPIC), any, TIMEOUT).await.retain);
+}
+
+#[tokio::test]
+async fn applies_settings() {
+    let broker = Broker::start().await;
+    let (_simulator, port) = start_simulator("settings", "");
+    let _mpqtt = MpqttConfig::new("settings", &broker).inverter(port).start();
+
+    // Control topics are subscribed before the update loop starts
+    broker.wait_for(&format!("{}/qpigs", TOPIC), any, TIMEOUT).await;
+
+    broker.publish(&format!("{}/set/output_source_priority", TOPIC), "solar");
+    let result = broker.wait_for(&format!("{}/set/output_source_priority/result", TOPIC), any, TIMEOUT).await;
+    assert_eq!(result.payload_str(), "ACK");
+    broker.wait_for(&format!("{}/settings", TOPIC), |message| message.payload_json()["output_source_priority"] == "solar", TIMEOUT).await;
+
+    broker.publish(&format!("{}/set/max_charging_current", TOPIC), "35");
+    let result = broker.wait_for(&format!("{}/set/max_charging_current/result", TOPIC), any, TIMEOUT).await;
+    assert_eq!(result.payload_str(), "NAK");
+}
+
+#[tokio::test]
+async fn shuts_down_on_sigterm() {
+    let broker = Broker::start().await;
+    let (_simulator, port) = start_simulator("shutdown", "");
+    let mut mpqtt = MpqttConfig::new("shutdown", &broker).inverter(port).start();
+
+    broker.wait_for(&format!("{}/qpigs", TOPIC), any, TIMEOUT).await;
+    assert!(mpqtt.terminate(TIMEOUT).await);
+
+    // Both availability topics are left offline and the error cleared
+    let availability = broker.messages(&format!("{}/availability", TOPIC));
+    assert_eq!(availability.last().unwrap().payload_str(), "offline");
+    assert!(availability.last().unwrap().retain);
+    assert_eq!(broker.messages(&format!("{}/inverter/availability", TOPIC)).last().unwrap().payload_str(), "offline");
+    assert!(broker.messages(&format!("{}/error", TOPIC)).last().unwrap().payload.is_empty());
+}
+
+#[tokio::test]
+async fn prints_state_once() {
+    let broker = Broker::start().await;
+    let (_simulator, port) = start_simulator("once", "");
+    let output = MpqttConfig::new("once", &broker).inverter(port).run(&["--once"], &[]);
+    assert!(output.status.success());
+
+    // Only the json goes to stdout and nothing is published
+    let state: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!((state["qpigs"]["battery_voltage"].as_f64().unwrap() - 52.8).abs() < 0.01);
+    assert!((state["qpiri"]["battery_rating_voltage"].as_f64().unwrap() - 48.0).abs() < 0.01);
+    assert!(state["qpiws"].is_object());
+    assert!(broker.messages(&format!("{}/availability", TOPIC)).is_empty());
+}
+
+#[tokio::test]
+async fn checks_config() {
+    let broker = Broker::start().await;
+    assert!(MpqttConfig::new("check-config", &broker).inverter(5000).run(&["--check-config"], &[]).status.success());
+    assert!(!MpqttConfig::new("check-config", &broker).inverter(5000).run(&["--check-config", "--config", "missing.yaml"], &[]).status.success());
+    assert!(!MpqttConfig::new("check-config", &broker).inverter(5000).run(&["--check-config", "--log-level", "loud"], &[]).status.success());
+}
+
+#[tokio::test]
+async fn reads_environment_overrides() {
+    let broker = Broker::start().await;
+    let password_file = std::env::temp_dir().join(format!("mpqtt-test-password-{}", std::process::id()));
+    std::fs::write(&password_file, "secret\n").unwrap();
+
+    assert!(MpqttConfig::new("environment", &broker).inverter(5000).run(&["--check-config"], &[("MPQTT_MQTT__PASSWORD_FILE", password_file.to_str().unwrap())]).status.success());
+    assert!(!MpqttConfig::new("environment", &broker).inverter(5000).run(&["--check-config"], &[("MPQTT_MQTT__PASSWORD_FILE", "missing-password")]).status.success());
+    assert!(!MpqttConfig::new("environment", &broker).inverter(5000).run(&["--check-config"], &[("MPQTT_INVERTER__MAX_FAILURES", "many")]).status.success());
+}
+
+#[tokio::test]
+async fn reports_every_config_problem() {
+    let broker = Broker::start().await;
+    let output = MpqttConfig::new("validation", &broker).inverter(5000).run(&["--check-config"], &[("MPQTT_MQTT__TOPIC", "mpqtt/#"), ("MPQTT_MQTT__DISCOVERY__PREFIX", "homeassistant/")]);
+    assert!(!output.status.success());
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("2 problem(s) found"), "{}", stderr);
+    assert!(stderr.contains("mqtt.topic: mpqtt/# must not contain the + or # wildcards"), "{}", stderr);
+    assert!(stderr.contains("mqtt.discovery.prefix: homeassistant/ must not start or end with /"), "{}", stderr);
+}
+
+#[tokio::test]
+async fn reports_unknown_settings() {
+    let broker = Broker::start().await;
+    let config = std::env::temp_dir().join(format!("mpqtt-test-unknown-{}.yaml", std::process::id()));
+    std::fs::write(&config, "inverter:\n  - path: tcp://127.0.0.1:5000\n    topic_suffix: first\n    pth: /dev/hidraw1\n").unwrap();
+
+    let output = MpqttConfig::new("unknown", &broker).inverter(5000).run(&["--check-config", "--config", config.to_str().unwrap()], &[("MPQTT_MQTT__HOTS", "broker")]);
+    assert!(!output.status.success());
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("2 problem(s) found"), "{}", stderr);
+    assert!(stderr.contains("inverter[0].pth: unknown setting"), "{}", stderr);
+    assert!(stderr.contains("mqtt.hots: unknown setting"), "{}", stderr);
+}
+
+#[tokio::test]
+async fn accepts_minimal_config() {
+    let broker = Broker::start().await;
+    let config = std::env::temp_dir().join(format!("mpqtt-test-minimal-{}.yaml", std::process::id()));
+    std::fs::write(&config, "inverter:\n  path: tcp://127.0.0.1:5000\n").unwrap();
+
+    let output = MpqttConfig::new("minimal", &broker).inverter(5000).run(&["--check-config", "--config", config.to_str().unwrap()], &[]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[tokio::test]
+async fn warns_about_missing_device() {
+    let broker = Broker::start().await;
+    let config = std::env::temp_dir().join(format!("mpqtt-test-missing-device-{}.yaml", std::process::id()));
+    std::fs::write(&config, "inverter:\n  path: /dev/mpqtt-missing\n").unwrap();
+
+    // The device may show up later, it is waited for instead of failing at startup
+    let output = MpqttConfig::new("missing-device", &broker).inverter(5000).run(&["--check-config", "--config", config.to_str().unwrap()], &[]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("inverter.path: /dev/mpqtt-missing does not exist yet"), "{}", stderr);
+}
+
+#[tokio::test]
+async fn polls_multiple_inverters() {
+    let broker = Broker::start().await;
+    let (_first, first_port) = start_simulator("multiple-first", "");
+    let (_second, second_port) = start_simulator("multiple-second", "0 battery_voltage 50.00\n");
+    let _mpqtt = MpqttConfig::new("multiple", &broker).inverter_with_suffix("first", first_port).inverter_with_suffix("second", second_port).start();
+
+    // Each inverter publishes under its own topic suffix
+    let first = broker.wait_for(&format!("{}/first/qpigs", TOPIC), any, TIMEOUT).await.payload_json();
+    let second = broker.wait_for(&format!("{}/second/qpigs", TOPIC), any, TIMEOUT).await.payload_json();
+    assert!((first["battery_voltage"].as_f64().unwrap() - 52.8).abs() < 0.01);
+    assert!((second["battery_voltage"].as_f64().unwrap() - 50.0).abs() < 0.01);
+    assert_eq!(broker.wait_for(&format!("{}/second/inverter/availability", TOPIC), any, TIMEOUT).await.payload_str(), "online");
+
+    // And registers its own Home Assistant device, sharing the bridge availability
+    let config = broker.wait_for(&format!("{}/sensor/mpqtt_second/qpigs_battery_voltage/config", DISCOVERY_PREFIX), any, TIMEOUT).await.payload_json();
+    assert_eq!(config["unique_id"], "mpqtt_second_qpigs_battery_voltage");
+    assert_eq!(config["state_topic"], format!("{}/second/qpigs", TOPIC));
+    assert_eq!(config["device"]["identifiers"], json!(["mpqtt_second"]));
+    assert_eq!(config["availability"], json!([{ "topic": format!("{}/availability", TOPIC) }, { "topic": format!("{}/second/inverter/availability", TOPIC) }]));
+
+    // Setting changes only reach the inverter they were sent to
+    broker.publish(&format!("{}/second/set/output_source_priority", TOPIC), "solar");
+    let result = broker.wait_for(&format!("{}/second/set/output_source_priority/result", TOPIC), any, TIMEOUT).await;
+    assert_eq!(result.payload_str(), "ACK");
+    assert!(broker.messages(&format!("{}/first/set/output_source_priority/result", TOPIC)).is_empty());
+}
+
+#[tokio::test]
+async fn polls_inverters_independently() {
+    let broker = Broker::start().await;
+    let (_first, first_port) = start_simulator("independent-first", "");
+    let (missing, missing_port) = start_simulator("independent-missing", "");
+    drop(missing);
+    let _mpqtt = MpqttConfig::new("independent", &broker).inverter_with_suffix("first", first_port).inverter_with_suffix("missing", missing_port).start();
+
+    // The inverter that can't be opened reports it and keeps being retried
+    broker.wait_for(&format!("{}/missing/error", TOPIC), |message| !message.payload.is_empty(), TIMEOUT).await;
+
+    // Without stopping the other one
+    broker.wait_for(&format!("{}/first/qpigs", TOPIC), any, TIMEOUT).await;
+    assert_eq!(broker.wait_for(&format!("{}/first/inverter/availability", TOPIC), any, TIMEOUT).await.payload_str(), "online");
+    assert!(broker.messages(&format!("{}/availability", TOPIC)).iter().all(|message| message.payload_str() == "online"));
+}
+
+#[tokio::test]
+async fn publishes_parallel_units() {
+    let broker = Broker::start().await;
+    let (_simulator, port) = start_simulator("parallel", "");
+
+    // The simulator only has 2 units, the third one answers NAK
+    let _mpqtt = MpqttConfig::new("parallel", &broker).inverter(port).parallel_units(3).start();
+
+    // Every unit publishes its own QPGSn state
+    let unit = broker.wait_for(&format!("{}/qpgs1", TOPIC), any, TIMEOUT).await.payload_json();
+    assert_eq!(unit["serial_number"], "92932004102441");
+    assert_eq!(unit["mode"], "line");
+    assert_eq!(unit["phase"], "parallel");
+    assert_eq!(unit["out_load_percent"], 9);
+    assert_eq!(unit["battery_charge_current"], 12);
+
+    // The totals add up every unit of the group
+    let totals = broker.wait_for(&format!("{}/parallel", TOPIC), any, TIMEOUT).await.payload_json();
+    assert_eq!(totals["units"], 2);
+    assert_eq!(totals["ac_out_active_power"], 840);
+    assert_eq!(totals["battery_charge_current"], 24);
+
+    // A missing unit is skipped without failing the connected inverter
+    broker.wait_for(&format!("{}/qpigs", TOPIC), any, TIMEOUT).await;
+    assert!(broker.messages(&format!("{}/qpgs2", TOPIC)).is_empty());
+    assert!(broker.messages(&format!("{}/inverter/availability", TOPIC)).iter().all(|message| message.payload_str() == "online"));
+
+    // And each unit is a Home Assistant device of its own
+    let config = broker.wait_for(&format!("{}/sensor/mpqtt/qpgs1_serial_number/config", DISCOVERY_PREFIX), any, TIMEOUT).await.payload_json();
+    assert_eq!(config["unique_id"], "mpqtt_qpgs1_serial_number");
+    assert_eq!(config["state_topic"], format!("{}/qpgs1", TOPIC));
+    assert_eq!(config["device"]["identifiers"], json!(["mpqtt_unit1"]));
+    assert_eq!(config["device"]["name"], "MPQTT unit 1");
+    let config = broker.wait_for(&format!("{}/sensor/mpqtt/parallel_ac_out_active_power/config", DISCOVERY_PREFIX), any, TIMEOUT).await.payload_json();
+    assert_eq!(config["device"]["identifiers"], json!(["mpqtt"]));
+}